@@ -2,7 +2,9 @@ use std::io::Read;
 use std::sync::LazyLock;
 
 use anyhow::{Context, Result, bail};
+use chrono::Utc;
 use libflate::gzip::Decoder;
+use liblzma::read::XzDecoder;
 use md5::Md5;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
@@ -11,11 +13,16 @@ use sha1::Sha1;
 use sha2::{Sha256, Sha512};
 
 use crate::{
+    cache,
     package::{Data, Package},
     utils::{Arch, hashsum},
 };
 
 static ARCH: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"Architecture: (\w+)"#).unwrap());
+static PACKAGE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"(?m)^Package: (\S+)"#).unwrap());
+static SECTION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"(?m)^Section: (\S+)"#).unwrap());
+static SOURCE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"(?m)^Source: (\S+)"#).unwrap());
+static VERSION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"(?m)^Version: (\S+)"#).unwrap());
 
 /// Debian package (.deb)
 #[derive(Serialize, Deserialize, Debug)]
@@ -27,6 +34,21 @@ pub struct DebianPackage {
     pub sha512: String,
     pub size: usize,
     pub filename: String,
+    /// Paths of the files installed by the package, without the leading `./`.
+    pub files: Vec<String>,
+    /// Signature members appended by PackHub to the `ar` archive, if the package was signed.
+    #[serde(default)]
+    pub signature: Option<Vec<u8>>,
+}
+
+/// Signing operations used to embed the signatures into the packages.
+pub struct DebSigner<'a> {
+    /// User ID of the signing key.
+    pub signer: &'a str,
+    /// Create a binary detached signature for the `debsig` `_gpgorigin` member.
+    pub detached_sign: &'a dyn Fn(&[u8]) -> Result<Vec<u8>>,
+    /// Create a cleartext signature for the `dpkg-sig` `_gpgbuilder` member.
+    pub clearsign: &'a dyn Fn(&str) -> Result<Vec<u8>>,
 }
 
 impl DebianPackage {
@@ -34,6 +56,19 @@ impl DebianPackage {
     ///
     /// Also sets metadata of the package.
     pub fn from_package(package: &Package) -> Result<Self> {
+        Self::parse(package, None)
+    }
+
+    /// Create a new Debian package from a package, with the `debsig` and `dpkg-sig`
+    /// signatures of the `signer` embedded into it.
+    ///
+    /// The signature members are appended to the archive, so the hashes and size describe
+    /// the package served by the pool proxy. Packages which are already signed are kept as is.
+    pub fn from_package_signed(package: &Package, signer: &DebSigner) -> Result<Self> {
+        Self::parse(package, Some(signer))
+    }
+
+    fn parse(package: &Package, signer: Option<&DebSigner>) -> Result<Self> {
         // Create the debian package from the metadata if it is present.
         if let Data::Metadata(metadata) = package.data() {
             let package: DebianPackage = from_str(&metadata)?;
@@ -49,13 +84,31 @@ impl DebianPackage {
             .context("Error occurred while parsing the debian control file from package")?
             .trim_end()
             .to_owned();
+        let files = read_file_list(&data).unwrap_or_else(|e| {
+            tracing::warn!("Unable to read the file list of {package:?}: {e}");
+            Vec::new()
+        });
         let filename = format!("pool/stable/{}/{}", package.version(), package.file_name());
 
+        let signature = match signer.map(|signer| signature_members(&data, signer)) {
+            Some(Ok(signature)) => signature,
+            Some(Err(e)) => {
+                tracing::warn!("Unable to sign {package:?}: {e}");
+                None
+            }
+            None => None,
+        };
+        let mut data = data;
+        if let Some(signature) = &signature {
+            data.extend_from_slice(signature);
+        }
+
         let size = data.len();
         let md5 = hashsum::<Md5>(&data);
         let sha1 = hashsum::<Sha1>(&data);
         let sha256 = hashsum::<Sha256>(&data);
         let sha512 = hashsum::<Sha512>(&data);
+        cache::store(&sha256, &data);
 
         let deb = Self {
             control,
@@ -65,6 +118,8 @@ impl DebianPackage {
             sha512,
             size,
             filename,
+            files,
+            signature,
         };
 
         let metadata = to_string(&deb)?;
@@ -83,6 +138,156 @@ impl DebianPackage {
             .parse()
             .ok()
     }
+
+    /// Get the name of the package.
+    pub fn get_name(&self) -> Option<&str> {
+        Some(PACKAGE.captures(&self.control)?.get(1)?.as_str())
+    }
+
+    /// Get the full `Description` field of the control file.
+    ///
+    /// The first line is the short description and the following lines,
+    /// which keep their leading space, form the long description.
+    pub fn get_description(&self) -> Option<String> {
+        let mut lines = self.control.lines();
+        let first = lines.find_map(|line| line.strip_prefix("Description:"))?;
+        let mut description = first.trim().to_owned();
+
+        for line in lines.take_while(|line| line.starts_with([' ', '\t'])) {
+            description.push('\n');
+            description.push_str(line);
+        }
+
+        Some(description)
+    }
+
+    /// Get the `Description-md5` of the package, which identifies its description in the
+    /// `Translation` indices.
+    pub fn get_description_md5(&self) -> Option<String> {
+        let description = self.get_description()?;
+        Some(hashsum::<Md5>(format!("{description}\n").as_bytes()))
+    }
+
+    /// Get the control data as it is published in the `Packages` index.
+    ///
+    /// The long description is moved to the `Translation-en` index, so only the short
+    /// description is kept along with the `Description-md5` field.
+    pub fn get_index_control(&self) -> String {
+        let Some(md5) = self.get_description_md5() else {
+            return self.control.clone();
+        };
+
+        let mut control = Vec::new();
+        let mut lines = self.control.lines().peekable();
+
+        while let Some(line) = lines.next() {
+            if !line.starts_with("Description:") {
+                control.push(line.to_owned());
+                continue;
+            }
+
+            control.push(line.to_owned());
+            control.push(format!("Description-md5: {md5}"));
+
+            while lines
+                .next_if(|line| line.starts_with([' ', '\t']))
+                .is_some()
+            {}
+        }
+
+        control.join("\n")
+    }
+
+    /// Get the `source_version` name of the changelog of the package, which ends the
+    /// `@CHANGEPATH@` requested by apt.
+    ///
+    /// The source is the package itself when it isn't given, and the epoch of the version
+    /// is left out.
+    pub fn get_changelog_name(&self) -> Option<String> {
+        let source = match SOURCE.captures(&self.control) {
+            Some(source) => source.get(1)?.as_str(),
+            None => self.get_name()?,
+        };
+        let version = VERSION.captures(&self.control)?.get(1)?.as_str();
+        let version = version
+            .split_once(':')
+            .map_or(version, |(_, version)| version);
+
+        Some(format!("{source}_{version}"))
+    }
+
+    /// Get the location of the package in the `section/name` form used by the `Contents` indices.
+    pub fn get_location(&self) -> Option<String> {
+        let name = self.get_name()?;
+
+        match SECTION.captures(&self.control).and_then(|c| c.get(1)) {
+            Some(section) => Some(format!("{}/{name}", section.as_str())),
+            None => Some(name.to_owned()),
+        }
+    }
+}
+
+/// Whether to embed the PackHub signatures into the packages, for the clients enforcing `debsig-verify`.
+///
+/// It can be enabled with the `PACKHUB_DEB_SIGN` variable.
+pub fn sign_packages() -> bool {
+    dotenvy::var("PACKHUB_DEB_SIGN").is_ok_and(|v| v == "true")
+}
+
+/// Create the `_gpgorigin` and `_gpgbuilder` signature members of the package.
+///
+/// `None` is returned if the package is already signed.
+fn signature_members(data: &[u8], signer: &DebSigner) -> Result<Option<Vec<u8>>> {
+    let mut archive = ar::Archive::new(data);
+    let mut members = Vec::new();
+
+    while let Some(entry_result) = archive.next_entry() {
+        let mut entry = entry_result?;
+        let name = String::from_utf8_lossy(entry.header().identifier()).into_owned();
+        if name.starts_with("_gpg") {
+            return Ok(None);
+        }
+
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        members.push((name, content));
+    }
+
+    // `debsig` signs the concatenation of the members
+    let contents: Vec<&[u8]> = members
+        .iter()
+        .map(|(_, content)| content.as_slice())
+        .collect();
+    let origin = (signer.detached_sign)(&contents.concat())?;
+
+    // `dpkg-sig` signs a manifest with the checksums of the members
+    let date = Utc::now();
+    let mut manifest = format!(
+        "Version: 4\nSigner: {}\nDate: {}\nRole: builder\nFiles: \n",
+        signer.signer,
+        date.to_rfc2822()
+    );
+    for (name, content) in members.iter() {
+        manifest.push_str(&format!(
+            "\t{} {} {} {name}\n",
+            hashsum::<Md5>(content),
+            hashsum::<Sha1>(content),
+            content.len()
+        ));
+    }
+    let builder = (signer.clearsign)(&manifest)?;
+
+    let mut archive = ar::Builder::new(Vec::new());
+    for (name, content) in [("_gpgorigin", origin), ("_gpgbuilder", builder)] {
+        let mut header = ar::Header::new(name.as_bytes().to_vec(), content.len() as u64);
+        header.set_mtime(date.timestamp() as u64);
+        header.set_mode(0o100644);
+        archive.append(&header, content.as_slice())?;
+    }
+
+    // Strip the global header of the archive
+    let archive = archive.into_inner()?;
+    Ok(Some(archive[8..].to_vec()))
 }
 
 fn read_control_file(data: &[u8]) -> Result<String> {
@@ -122,12 +327,70 @@ fn read_control_file(data: &[u8]) -> Result<String> {
     bail!("Control file not found");
 }
 
+/// Read the list of files contained in the `data.tar.*` archive of the package.
+fn read_file_list(data: &[u8]) -> Result<Vec<String>> {
+    let mut archive = ar::Archive::new(data);
+
+    while let Some(entry_result) = archive.next_entry() {
+        let mut entry = entry_result?;
+        let header = entry.header();
+        let name = String::from_utf8_lossy(header.identifier()).into_owned();
+        if !name.starts_with("data.tar") {
+            continue;
+        }
+
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+
+        let data = match name.as_str() {
+            "data.tar" => data,
+            "data.tar.gz" => {
+                let mut decoded = Vec::new();
+                Decoder::new(&data[..])?.read_to_end(&mut decoded)?;
+                decoded
+            }
+            "data.tar.xz" => {
+                let mut decoded = Vec::new();
+                XzDecoder::new(&data[..]).read_to_end(&mut decoded)?;
+                decoded
+            }
+            "data.tar.zst" => zstd::decode_all(&data[..])?,
+            name => bail!("Unsupported data archive: {name}"),
+        };
+
+        let mut files = Vec::new();
+        let mut archive = tar::Archive::new(&data[..]);
+        for entry in archive.entries()? {
+            let entry = entry?;
+
+            if entry.header().entry_type().is_dir() {
+                continue;
+            }
+
+            let path = entry.path()?;
+            let path = path.to_string_lossy();
+            files.push(path.trim_start_matches("./").to_owned());
+        }
+
+        return Ok(files);
+    }
+
+    bail!("Data archive not found");
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::read;
 
+    use sequoia_openpgp::{
+        Packet, cert::CertBuilder, crypto::Password, parse::Parse, policy::StandardPolicy,
+    };
+
     use super::*;
-    use crate::package::tests::package;
+    use crate::{
+        package::tests::{package, package_with_ver},
+        pgp::{clearsign_metadata, detached_sign_binary},
+    };
 
     #[test]
     fn test_parsing() {
@@ -160,4 +423,117 @@ mod tests {
 
         let _ = DebianPackage::from_package(&package).unwrap();
     }
+
+    #[test]
+    fn test_description() {
+        let package = package("fastfetch-linux-amd64.deb");
+        let data = read("data/fastfetch-linux-amd64.deb").unwrap();
+        package.set_package_data(data);
+
+        let deb = DebianPackage::from_package(&package).unwrap();
+        assert_eq!(
+            deb.get_description_md5().as_deref(),
+            Some("09d225874d701d23f711a336d87efe84")
+        );
+
+        let control = deb.get_index_control();
+        assert!(control.contains(
+            "Description: Fast neofetch-like system information tool\nDescription-md5: 09d225874d701d23f711a336d87efe84\nHomepage:"
+        ));
+        assert_eq!(
+            deb.get_changelog_name().as_deref(),
+            Some("fastfetch_2.40.3")
+        );
+    }
+
+    #[test]
+    fn test_package_signing() {
+        let passphrase: Password = "passphrase".into();
+        let (cert, _) = CertBuilder::new()
+            .set_password(Some(passphrase.clone()))
+            .add_signing_subkey()
+            .generate()
+            .unwrap();
+        let signer = DebSigner {
+            signer: "PackHub <sign@packhub.dev>",
+            detached_sign: &|data| detached_sign_binary(data, &cert, &passphrase),
+            clearsign: &|data| clearsign_metadata(data, &[&cert], &passphrase),
+        };
+
+        let package = package("fastfetch-linux-amd64.deb");
+        let data = read("data/fastfetch-linux-amd64.deb").unwrap();
+        package.set_package_data(data.clone());
+        let deb = DebianPackage::from_package_signed(&package, &signer).unwrap();
+
+        // The proxied package must match the metadata
+        let signed = [&data[..], deb.signature.as_ref().unwrap()].concat();
+        assert_eq!(deb.sha256, hashsum::<Sha256>(&signed));
+        assert_eq!(deb.size, signed.len());
+
+        let mut archive = ar::Archive::new(&signed[..]);
+        let mut members = Vec::new();
+        while let Some(entry) = archive.next_entry() {
+            let mut entry = entry.unwrap();
+            let name = String::from_utf8_lossy(entry.header().identifier()).into_owned();
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).unwrap();
+            members.push((name, content));
+        }
+        let names: Vec<&str> = members.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["control.tar.gz", "_gpgorigin", "_gpgbuilder"]);
+
+        // The origin signature covers the concatenation of the package members
+        let Packet::Signature(signature) = Packet::from_bytes(&members[1].1).unwrap() else {
+            panic!("The origin signature isn't an OpenPGP signature");
+        };
+        let policy = StandardPolicy::new();
+        let key = cert
+            .keys()
+            .with_policy(&policy, None)
+            .for_signing()
+            .next()
+            .unwrap();
+        signature.verify_message(key.key(), &members[0].1).unwrap();
+
+        let builder = String::from_utf8(members[2].1.clone()).unwrap();
+        assert!(builder.starts_with("-----BEGIN PGP SIGNED MESSAGE-----"));
+        assert!(builder.contains("Role: builder\n"));
+        assert!(builder.contains(&format!(
+            "\t{} {} {} control.tar.gz\n",
+            hashsum::<Md5>(&members[0].1),
+            hashsum::<Sha1>(&members[0].1),
+            members[0].1.len()
+        )));
+
+        // Already signed packages are kept as is
+        let package = package_with_ver("fastfetch-linux-amd64.deb", "2.40.4");
+        package.set_package_data(signed.clone());
+        let deb = DebianPackage::from_package_signed(&package, &signer).unwrap();
+        assert!(deb.signature.is_none());
+        assert_eq!(deb.sha256, hashsum::<Sha256>(&signed));
+    }
+
+    #[test]
+    fn test_file_list() {
+        let mut tar = tar::Builder::new(Vec::new());
+
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        tar.append_data(&mut header, "./usr/bin/", &[][..]).unwrap();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        tar.append_data(&mut header, "./usr/bin/hello", &b"hello"[..])
+            .unwrap();
+
+        let data = zstd::encode_all(&tar.into_inner().unwrap()[..], 0).unwrap();
+
+        let mut deb = ar::Builder::new(Vec::new());
+        let header = ar::Header::new(b"data.tar.zst".to_vec(), data.len() as u64);
+        deb.append(&header, &data[..]).unwrap();
+
+        let files = read_file_list(&deb.into_inner().unwrap()).unwrap();
+        assert_eq!(files, vec!["usr/bin/hello".to_owned()]);
+    }
 }
@@ -1,40 +1,92 @@
-use std::{collections::HashMap, io::Write};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::OnceLock,
+};
 
 use anyhow::Result;
 use askama::Template;
-use chrono::{DateTime, Utc};
-use libflate::gzip::{EncodeOptions, Encoder, HeaderBuilder};
+use chrono::{DateTime, TimeDelta, Utc};
 use md5::Md5;
 use sha1::Sha1;
 use sha2::{Sha256, Sha512};
 
 use crate::{
-    apt::deb::DebianPackage,
+    apt::deb::{DebSigner, DebianPackage},
     package::Package,
-    utils::{Arch, hashsum},
+    utils::{Arch, Compression, hashsum},
 };
 
+/// Release files are re-signed once in every interval, so that the same Release
+/// file is served for all the requests made in between.
+const RESIGN_INTERVAL: TimeDelta = TimeDelta::days(1);
+
+/// Duration after the `Date` of a Release file until which clients accept it.
+pub const VALIDITY: TimeDelta = TimeDelta::days(7);
+
+/// Start of the current re-signing period of the Release files.
+pub fn signing_period() -> DateTime<Utc> {
+    let now = Utc::now().timestamp();
+    DateTime::from_timestamp(now - now % RESIGN_INTERVAL.num_seconds(), 0).unwrap_or_default()
+}
+
 #[derive(Debug)]
 pub struct AptIndices {
     packages: HashMap<Arch, Vec<DebianPackage>>,
     date: DateTime<Utc>,
+    signed_at: DateTime<Utc>,
+    /// Index files listed in the Release file, generated on the first use.
+    files: OnceLock<Vec<(String, Vec<u8>)>>,
+}
+
+/// Repository specific fields of the Release file.
+pub struct ReleaseInfo<'a> {
+    pub origin: &'a str,
+    pub label: &'a str,
+    /// Fingerprints of the keys signing the Release file, separated by commas.
+    pub signed_by: &'a str,
+    /// URL pattern of the changelogs with the `@CHANGEPATH@` placeholder.
+    pub changelogs: &'a str,
 }
 
 #[derive(Template)]
 #[template(path = "Release")]
 struct ReleaseIndex<'a> {
-    origin: &'a str,
-    label: &'a str,
+    info: &'a ReleaseInfo<'a>,
     date: String,
+    valid_until: String,
+    architectures: String,
     files: Vec<Files>,
 }
 
 #[derive(Template)]
 #[template(path = "Packages")]
 struct PackageIndex<'a> {
-    packages: &'a [DebianPackage],
+    packages: Vec<&'a DebianPackage>,
+}
+
+/// Every variant of the `Packages` index that is published for an architecture.
+const PACKAGES_FILES: [&str; 4] = ["Packages", "Packages.gz", "Packages.xz", "Packages.zst"];
+
+#[derive(Template)]
+#[template(path = "Translation-en")]
+struct TranslationIndex {
+    translations: Vec<Translation>,
+}
+
+struct Translation {
+    package: String,
+    md5: String,
+    description: String,
 }
 
+/// Every variant of the `Translation-en` index that is published.
+const TRANSLATION_FILES: [&str; 4] = [
+    "Translation-en",
+    "Translation-en.gz",
+    "Translation-en.xz",
+    "Translation-en.zst",
+];
+
 struct Files {
     md5: String,
     sha1: String,
@@ -46,7 +98,17 @@ struct Files {
 
 impl AptIndices {
     pub fn new(packages: &[Package]) -> Result<AptIndices> {
-        let mut debian: HashMap<Arch, Vec<DebianPackage>> = HashMap::new();
+        Self::build(packages, None)
+    }
+
+    /// Create the indices of the packages signed by the `signer`.
+    pub fn new_signed(packages: &[Package], signer: &DebSigner) -> Result<AptIndices> {
+        Self::build(packages, Some(signer))
+    }
+
+    fn build(packages: &[Package], signer: Option<&DebSigner>) -> Result<AptIndices> {
+        // Packages along with the architecture they were built for
+        let mut debian: HashMap<Arch, Vec<(Arch, DebianPackage)>> = HashMap::new();
         // Find the latest date from the list of packages
         let mut date = DateTime::UNIX_EPOCH;
         for package in packages {
@@ -54,10 +116,31 @@ impl AptIndices {
                 date = *package.creation_date();
             }
 
-            match DebianPackage::from_package(package) {
+            let deb = match signer {
+                Some(signer) => DebianPackage::from_package_signed(package, signer),
+                None => DebianPackage::from_package(package),
+            };
+
+            match deb {
                 Ok(deb) => {
                     if let Some(arch) = deb.get_arch() {
-                        debian.entry(arch).or_default().push(deb);
+                        let debs = debian.entry(arch).or_default();
+                        let build = package.architecture();
+
+                        // Builds for different ABIs (e.g. ARMv6 and ARMv7) can share the same
+                        // Debian architecture. Only keep the most preferred one of them.
+                        match debs
+                            .iter()
+                            .position(|(_, d)| d.get_name() == deb.get_name())
+                        {
+                            Some(idx)
+                                if preference(&arch, &build) < preference(&arch, &debs[idx].0) =>
+                            {
+                                debs[idx] = (build, deb)
+                            }
+                            Some(_) => continue,
+                            None => debs.push((build, deb)),
+                        }
                     } else {
                         tracing::error!(
                             "Debian package architecture not found for package: {:?}",
@@ -72,73 +155,226 @@ impl AptIndices {
                 }
             }
         }
+        let signed_at = signing_period();
+
+        let debian = debian
+            .into_iter()
+            .map(|(arch, debs)| (arch, debs.into_iter().map(|(_, deb)| deb).collect()))
+            .collect();
+
         Ok(AptIndices {
             packages: debian,
             date,
+            signed_at,
+            files: OnceLock::new(),
         })
     }
 
+    /// Get the packages published for `arch`.
+    ///
+    /// Like the Debian archive, architecture independent packages are
+    /// published for every architecture along with the `all` architecture.
+    fn packages_of(&self, arch: &Arch) -> Vec<&DebianPackage> {
+        let mut packages: Vec<&DebianPackage> =
+            self.packages.get(arch).into_iter().flatten().collect();
+
+        if *arch != Arch::All {
+            packages.extend(self.packages.get(&Arch::All).into_iter().flatten());
+        }
+
+        packages
+    }
+
     pub fn get_package_index(&self, arch: &Arch) -> String {
         let index = PackageIndex {
-            packages: self.packages.get(arch).unwrap(),
+            packages: self.packages_of(arch),
         };
         index.render().unwrap().trim().to_owned()
     }
 
-    pub fn get_release_index(&self) -> String {
-        let name = ". stable";
-        let date = self.date.to_rfc2822();
+    /// Get the `file` variant (`Packages`, `Packages.gz`, etc) of the package index of `arch`.
+    ///
+    /// `None` is returned if the `file` is not a known variant.
+    pub fn get_package_file(&self, arch: &Arch, file: &str) -> Option<Vec<u8>> {
+        compress_index(file, self.get_package_index(arch).as_bytes())
+    }
+
+    /// Get the `Contents` index of `arch`, which maps every file to the packages shipping it.
+    pub fn get_contents_index(&self, arch: &Arch) -> String {
+        let mut contents: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+
+        for package in self.packages_of(arch) {
+            let Some(location) = package.get_location() else {
+                continue;
+            };
+
+            for file in package.files.iter() {
+                contents.entry(file).or_default().push(location.clone());
+            }
+        }
+
+        let mut index = String::new();
+
+        for (file, locations) in contents {
+            index.push_str(&format!("{file:<55} {}\n", locations.join(",")));
+        }
+
+        index
+    }
+
+    /// Get the `file` variant (`Contents-amd64`, `Contents-amd64.gz`, etc) of the contents index of `arch`.
+    ///
+    /// `None` is returned if the `file` is not a known variant.
+    pub fn get_contents_file(&self, arch: &Arch, file: &str) -> Option<Vec<u8>> {
+        compress_index(file, self.get_contents_index(arch).as_bytes())
+    }
+
+    /// Get the `Translation-en` index containing the long descriptions of the packages.
+    pub fn get_translation_index(&self) -> String {
+        let mut translations: BTreeMap<(String, String), String> = BTreeMap::new();
+
+        for package in self.packages.values().flatten() {
+            let (Some(name), Some(description), Some(md5)) = (
+                package.get_name(),
+                package.get_description(),
+                package.get_description_md5(),
+            ) else {
+                continue;
+            };
+
+            translations.insert((name.to_owned(), md5), description);
+        }
+
+        let translations = translations
+            .into_iter()
+            .map(|((package, md5), description)| Translation {
+                package,
+                md5,
+                description,
+            })
+            .collect();
+
+        let index = TranslationIndex { translations };
+        index.render().unwrap()
+    }
+
+    /// Get the `file` variant (`Translation-en`, `Translation-en.gz`, etc) of the translation index.
+    ///
+    /// `None` is returned if the `file` is not a known variant.
+    pub fn get_translation_file(&self, file: &str) -> Option<Vec<u8>> {
+        if !TRANSLATION_FILES.contains(&file) {
+            return None;
+        }
+
+        compress_index(file, self.get_translation_index().as_bytes())
+    }
+
+    /// Get the index files listed in the Release file along with their paths.
+    fn index_files(&self) -> &[(String, Vec<u8>)] {
+        self.files.get_or_init(|| {
+            let mut files = vec![];
+
+            for arch in self.packages.keys() {
+                for file in PACKAGES_FILES {
+                    let data = self.get_package_file(arch, file).unwrap();
+                    files.push((format!("main/binary-{}/{}", arch.dpkg_name(), file), data));
+                }
+
+                let file = format!("Contents-{}.gz", arch.dpkg_name());
+                let data = self.get_contents_file(arch, &file).unwrap();
+                files.push((format!("main/{}", file), data));
+            }
+
+            for file in TRANSLATION_FILES {
+                let data = self.get_translation_file(file).unwrap();
+                files.push((format!("main/i18n/{}", file), data));
+            }
+
+            files
+        })
+    }
+
+    /// Get the index files listed in the Release file along with the directories they are
+    /// located in.
+    ///
+    /// They are kept to serve the `by-hash` requests of apt, as the clients can request them
+    /// after the Release file has changed.
+    pub fn by_hash_files(&self) -> Vec<(&str, &[u8])> {
+        self.index_files()
+            .iter()
+            .filter_map(|(path, data)| {
+                let (dir, _) = path.rsplit_once('/')?;
+                Some((dir, data.as_slice()))
+            })
+            .collect()
+    }
+
+    /// Date of the last change of the Release file, which is also re-signed periodically.
+    pub fn release_modified(&self) -> DateTime<Utc> {
+        self.date.max(self.signed_at)
+    }
+
+    pub fn get_release_index(&self, info: &ReleaseInfo) -> String {
+        let date = self.date.max(self.signed_at);
+        let valid_until = (date + VALIDITY).to_rfc2822();
+        let date = date.to_rfc2822();
+
+        let architectures = self
+            .packages
+            .keys()
+            .map(|arch| arch.dpkg_name())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>()
+            .join(" ");
 
         let mut files = vec![];
 
-        for arch in self.packages.keys() {
-            let packages = self.get_package_index(arch);
-            let packages = packages.as_bytes();
-            let packages_gz = gzip_compression(packages);
-
-            files.extend([
-                Files {
-                    sha256: hashsum::<Sha256>(packages),
-                    size: packages.len(),
-                    path: format!("main/binary-{}/Packages", arch),
-                    md5: hashsum::<Md5>(packages),
-                    sha1: hashsum::<Sha1>(packages),
-                    sha512: hashsum::<Sha512>(packages),
-                },
-                Files {
-                    sha256: hashsum::<Sha256>(&packages_gz),
-                    size: packages_gz.len(),
-                    path: format!("main/binary-{}/Packages.gz", arch),
-                    md5: hashsum::<Md5>(&packages_gz),
-                    sha1: hashsum::<Sha1>(&packages_gz),
-                    sha512: hashsum::<Sha512>(&packages_gz),
-                },
-            ]);
+        for (path, data) in self.index_files() {
+            files.push(Files {
+                sha256: hashsum::<Sha256>(data),
+                size: data.len(),
+                path: path.clone(),
+                md5: hashsum::<Md5>(data),
+                sha1: hashsum::<Sha1>(data),
+                sha512: hashsum::<Sha512>(data),
+            });
         }
 
         // Sort the files
         files.sort_by(|a, b| a.path.cmp(&b.path));
 
         let index = ReleaseIndex {
+            info,
             date,
+            valid_until,
+            architectures,
             files,
-            origin: name,
-            label: name,
         };
 
         index.render().unwrap()
     }
 }
 
-pub fn gzip_compression(data: &[u8]) -> Vec<u8> {
-    let header = HeaderBuilder::new().modification_time(0).finish();
-    let options = EncodeOptions::new().header(header);
-    let mut encoder = Encoder::with_options(Vec::new(), options).unwrap();
-    encoder.write_all(data).unwrap();
+/// Preference of a package built for `build` to be installed on `arch`.
+///
+/// Lower is better.
+fn preference(arch: &Arch, build: &Arch) -> usize {
+    arch.compatible()
+        .iter()
+        .position(|a| a == build)
+        .unwrap_or(usize::MAX)
+}
 
-    let gzip = encoder.finish();
+/// Compress the `data` of an index according to the extension of the requested `file`.
+///
+/// `None` is returned if the extension is unknown.
+pub fn compress_index(file: &str, data: &[u8]) -> Option<Vec<u8>> {
+    if !file.contains('.') {
+        return Some(data.to_vec());
+    }
 
-    gzip.into_result().unwrap()
+    Compression::split(file).map(|(_, compression)| compression.compress(data))
 }
 
 #[cfg(test)]
@@ -149,7 +385,14 @@ mod tests {
     use insta::assert_snapshot;
 
     use super::*;
-    use crate::package::tests::package_with_ver;
+    use crate::{package::tests::package_with_ver, utils::gzip_compression};
+
+    const INFO: ReleaseInfo = ReleaseInfo {
+        origin: "OpenBangla",
+        label: "OpenBangla-Keyboard",
+        signed_by: "6E1F8F5F4C9A2D0E3B7A1C5D8E2F4A6B9C0D1E2F",
+        changelogs: "http://localhost:3000/v1/apt/ubuntu/github/OpenBangla/OpenBangla-Keyboard/changelogs/@CHANGEPATH@",
+    };
 
     #[test]
     fn test_apt_indices() {
@@ -159,14 +402,15 @@ mod tests {
 
         let packages = vec![package];
 
-        let indices = AptIndices::new(&packages).unwrap();
+        let mut indices = AptIndices::new(&packages).unwrap();
+        indices.signed_at = DateTime::UNIX_EPOCH;
 
         // Packages
         let packages = indices.get_package_index(&Arch::Amd64);
         assert_snapshot!(packages);
 
         // Release
-        let release = indices.get_release_index();
+        let release = indices.get_release_index(&INFO);
         assert_snapshot!(release);
     }
 
@@ -182,17 +426,18 @@ mod tests {
 
         let packages = vec![package1, package2];
 
-        let indices = AptIndices::new(&packages).unwrap();
+        let mut indices = AptIndices::new(&packages).unwrap();
+        indices.signed_at = DateTime::UNIX_EPOCH;
 
         // Packages
         let packages = indices.get_package_index(&Arch::Amd64);
         assert_snapshot!(packages);
-        assert_eq!(packages.as_bytes().len(), 2729);
+        assert_eq!(packages.len(), 1954);
         let packages_gz = gzip_compression(packages.as_bytes());
-        assert_eq!(packages_gz.len(), 1105);
+        assert_eq!(packages_gz.len(), 906);
 
         // Release
-        let release = indices.get_release_index();
+        let release = indices.get_release_index(&INFO);
         assert_snapshot!(release);
     }
 
@@ -230,10 +475,179 @@ mod tests {
             package1, package2, package3, package4, package5, package6, package7,
         ];
 
-        let indices = AptIndices::new(&packages).unwrap();
+        let mut indices = AptIndices::new(&packages).unwrap();
+        indices.signed_at = DateTime::UNIX_EPOCH;
 
         // Release
-        let release = indices.get_release_index();
+        let release = indices.get_release_index(&INFO);
+        assert_snapshot!(release);
+
+        // Translation-en
+        let translation = indices.get_translation_index();
+        assert_snapshot!(translation);
+
+        for file in TRANSLATION_FILES {
+            assert!(indices.get_translation_file(file).is_some());
+        }
+        for file in ["Translation-de", "Translation-en_US", "Translation-en.bz2"] {
+            assert_eq!(indices.get_translation_file(file), None);
+        }
+    }
+
+    #[test]
+    fn test_index_files_by_hash() {
+        let package = package_with_ver("fastfetch-linux-amd64.deb", "2.40.3");
+        let data = fs::read("data/fastfetch-linux-amd64.deb").unwrap();
+        package.set_package_data(data);
+
+        let indices = AptIndices::new(&[package]).unwrap();
+        let files = indices.by_hash_files();
+        let release = indices.get_release_index(&INFO);
+
+        for file in PACKAGES_FILES {
+            let data = indices.get_package_file(&Arch::Amd64, file).unwrap();
+            assert!(files.contains(&("main/binary-amd64", data.as_slice())));
+            assert!(!files.contains(&("main", data.as_slice())));
+            assert!(release.contains(&hashsum::<Sha256>(&data)));
+        }
+
+        let data = indices
+            .get_contents_file(&Arch::Amd64, "Contents-amd64.gz")
+            .unwrap();
+        assert!(files.contains(&("main", data.as_slice())));
+
+        let data = indices.get_translation_file("Translation-en.xz").unwrap();
+        assert!(files.contains(&("main/i18n", data.as_slice())));
+
+        assert_eq!(
+            files.len(),
+            PACKAGES_FILES.len() + 1 + TRANSLATION_FILES.len()
+        );
+        assert_eq!(indices.get_package_file(&Arch::Amd64, "Packages.bz2"), None);
+    }
+
+    #[test]
+    fn test_contents_index() {
+        let control = "Package: hello\nSection: utils\nArchitecture: amd64".to_owned();
+        let deb = |control: &str, files: &[&str]| DebianPackage {
+            control: control.to_owned(),
+            md5: String::new(),
+            sha1: String::new(),
+            sha256: String::new(),
+            sha512: String::new(),
+            size: 0,
+            filename: String::new(),
+            files: files.iter().map(|f| f.to_string()).collect(),
+            signature: None,
+        };
+
+        let indices = AptIndices {
+            packages: HashMap::from([(
+                Arch::Amd64,
+                vec![
+                    deb(
+                        &control,
+                        &["usr/bin/hello", "usr/share/doc/hello/copyright"],
+                    ),
+                    deb(
+                        "Package: hello-extra\nArchitecture: amd64",
+                        &["usr/bin/hello"],
+                    ),
+                ],
+            )]),
+            date: DateTime::UNIX_EPOCH,
+            signed_at: DateTime::UNIX_EPOCH,
+            files: OnceLock::new(),
+        };
+
+        assert_snapshot!(indices.get_contents_index(&Arch::Amd64));
+    }
+
+    #[test]
+    fn test_contents_from_data_archive() {
+        let package = package_with_ver("packhub-hello_1.0.0_amd64.deb", "1.0.0");
+        let data = fs::read("data/packhub-hello_1.0.0_amd64.deb").unwrap();
+        package.set_package_data(data);
+
+        let mut indices = AptIndices::new(&[package]).unwrap();
+        indices.signed_at = DateTime::UNIX_EPOCH;
+
+        let contents = indices.get_contents_index(&Arch::Amd64);
+        assert_snapshot!(contents);
+
+        assert_eq!(
+            indices.get_contents_file(&Arch::Amd64, "Contents-amd64"),
+            Some(contents.clone().into_bytes())
+        );
+        for compression in [Compression::Gzip, Compression::Xz, Compression::Zstd] {
+            let file = format!("Contents-amd64.{}", compression.extension());
+            assert_eq!(
+                indices.get_contents_file(&Arch::Amd64, &file),
+                Some(compression.compress(contents.as_bytes()))
+            );
+        }
+
+        let release = indices.get_release_index(&INFO);
         assert_snapshot!(release);
     }
+
+    /// Indices of the `packhub-hello` fixture packages built for different architectures.
+    fn hello_indices(files: &[&str]) -> AptIndices {
+        let packages: Vec<Package> = files
+            .iter()
+            .map(|file| {
+                let package = package_with_ver(file, "1.0.0");
+                package.set_package_data(fs::read(format!("data/{file}")).unwrap());
+                package
+            })
+            .collect();
+
+        AptIndices::new(&packages).unwrap()
+    }
+
+    #[test]
+    fn test_architecture_all() {
+        let indices = hello_indices(&[
+            "packhub-hello_1.0.0_amd64.deb",
+            "packhub-hello-data_1.0.0_all.deb",
+        ]);
+
+        let index = indices.get_package_index(&Arch::Amd64);
+        assert!(index.contains("Package: packhub-hello\n"));
+        assert!(index.contains("Package: packhub-hello-data\n"));
+
+        let index = indices.get_package_index(&Arch::All);
+        assert!(!index.contains("Package: packhub-hello\n"));
+        assert!(index.contains("Package: packhub-hello-data\n"));
+
+        // Only architecture independent packages are available
+        let indices = hello_indices(&["packhub-hello-data_1.0.0_all.deb"]);
+        let release = indices.get_release_index(&INFO);
+        assert!(release.contains("Architectures: all\n"));
+        assert!(release.contains("main/binary-all/Packages\n"));
+    }
+
+    #[test]
+    fn test_foreign_architecture() {
+        let indices = hello_indices(&["packhub-hello_1.0.0_amd64.deb"]);
+
+        // Nothing is published for the foreign architecture
+        assert_eq!(indices.get_package_index(&Arch::I386), "");
+        assert_eq!(
+            indices.get_package_file(&Arch::I386, "Packages.gz"),
+            Some(gzip_compression(b""))
+        );
+
+        let indices = hello_indices(&[
+            "packhub-hello_1.0.0_amd64.deb",
+            "packhub-hello_1.0.0_i386.deb",
+        ]);
+        let index = indices.get_package_index(&Arch::I386);
+        assert!(index.contains("Architecture: i386\n"));
+        assert!(!index.contains("Architecture: amd64\n"));
+
+        let release = indices.get_release_index(&INFO);
+        assert!(release.contains("Architectures: amd64 i386\n"));
+        assert!(release.contains("main/binary-i386/Packages\n"));
+    }
 }
@@ -2,6 +2,6 @@ mod deb;
 mod index;
 mod routes;
 
-pub use self::routes::apt_routes;
+pub use self::{deb::sign_packages, routes::apt_routes};
 #[cfg(test)]
 pub use deb::DebianPackage;
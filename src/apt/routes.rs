@@ -1,98 +1,431 @@
-use anyhow::{Context, anyhow};
+use anyhow::{Context, Result, anyhow};
 use axum::{
     Router,
-    body::Body,
-    extract::{Path, State},
-    response::IntoResponse,
+    extract::{Path, Request, State},
+    http::HeaderMap,
+    response::Response,
     routing::get,
 };
 use axum_extra::{headers::UserAgent, typed_header::TypedHeader};
+use chrono::Utc;
+use serde_json::from_str;
 
 use crate::{
-    REQWEST,
-    apt::index::{AptIndices, gzip_compression},
+    apt::{
+        deb::{DebSigner, DebianPackage, sign_packages},
+        index::{AptIndices, ReleaseInfo, VALIDITY, compress_index, signing_period},
+    },
+    conditional::Index,
+    db::{IndexFile, PackageMetadata, ReleaseMetadata},
     error::AppError,
+    ident::{AssetPath, Owner, Repo, Tag, project},
+    package::{Package, Verification},
+    proxy::{self, Asset, Expected},
     repository::Repository,
-    state::AppState,
-    utils::Arch,
+    state::{AppState, SigningKeys},
+    utils::{Arch, Compression},
 };
 
 #[tracing::instrument(name = "Debian Release File", skip_all, fields(agent = agent.as_str()))]
 async fn release_index(
     State(state): State<AppState>,
-    Path((distro, owner, repo, file)): Path<(String, String, String, String)>,
+    Path((distro, owner, repo, file)): Path<(String, Owner, Repo, String)>,
     TypedHeader(agent): TypedHeader<UserAgent>,
-) -> Result<Vec<u8>, AppError> {
-    let mut repo = Repository::from_github(owner, repo, &state).await;
-    let packages = repo.select_package_apt(&distro, agent.as_str()).await?;
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let host = dotenvy::var("PACKHUB_DOMAIN")?;
+    let changelogs =
+        format!("{host}/v1/apt/{distro}/github/{owner}/{repo}/changelogs/@CHANGEPATH@");
+    let release = Repository::latest_release(&state, &owner, &repo).await?;
+    let keys = state
+        .released_repository_keys(&owner, &repo, &release)
+        .await?;
+    let fingerprints = keys.fingerprints();
+    let info = ReleaseInfo {
+        origin: &owner,
+        label: &repo,
+        signed_by: &fingerprints,
+        changelogs: &changelogs,
+    };
 
-    let index = AptIndices::new(&packages)?;
-    repo.save_package_metadata().await;
+    let signed_by = sign_packages().then(|| keys.fingerprint());
+    let mut repository =
+        Repository::from_release(owner.clone(), repo.clone(), &state, release, signed_by).await;
+
+    let signed_at = signing_period().to_rfc3339();
+    let tag = repository.client_tag(&[&distro, agent.as_str(), &file, &fingerprints, &signed_at]);
+    if let Some(response) = tag.not_modified(&headers) {
+        return Ok(response);
+    }
+
+    let packages = repository
+        .select_package_apt(&distro, agent.as_str())
+        .await?;
+
+    let index = keys
+        .blocking(move |keys| apt_indices(keys, &packages))
+        .await?;
+    repository.save_package_metadata().await;
 
-    let release_file = index.get_release_index();
+    let release_file = index.get_release_index(&info);
+    store_index_files(&state, &project(&owner, &repo), &index, &release_file).await;
+    let modified = index.release_modified();
 
-    match file.as_str() {
-        "Release" => Ok(release_file.into_bytes()),
+    let index = match file.as_str() {
+        "Release" => Index::new(release_file.into_bytes(), modified),
         "Release.gpg" => {
-            let signed_release_file = state.detached_sign_metadata(&release_file)?;
-            Ok(signed_release_file)
+            let release = release_file.clone();
+            let signed_release_file = keys
+                .blocking(move |keys| keys.detached_sign_metadata(&release))
+                .await?;
+            Index::signature(signed_release_file, release_file.as_bytes(), modified)
         }
         "InRelease" => {
-            let signed_release_file = state.clearsign_metadata(&release_file)?;
-            Ok(signed_release_file)
+            let release = release_file.clone();
+            let signed_release_file = keys
+                .blocking(move |keys| keys.clearsign_metadata(&release))
+                .await?;
+            Index::signature(signed_release_file, release_file.as_bytes(), modified)
         }
-        file => Err(anyhow!("Unknown file requested: {file}").into()),
-    }
+        file => return Err(anyhow!("Unknown file requested: {file}").into()),
+    };
+
+    Ok(index.tagged(&tag).respond(&headers))
 }
 
 #[tracing::instrument(name = "Debian Package metadata file", skip_all, fields(agent = agent.as_str()))]
 async fn packages_file(
     State(state): State<AppState>,
-    Path((distro, owner, repo, arch, file)): Path<(String, String, String, String, String)>,
+    Path((distro, owner, repo, arch, file)): Path<(String, Owner, Repo, String, String)>,
     TypedHeader(agent): TypedHeader<UserAgent>,
-) -> Result<Vec<u8>, AppError> {
-    let mut repo = Repository::from_github(owner, repo, &state).await;
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let release = Repository::latest_release(&state, &owner, &repo).await?;
+    let keys = state
+        .released_repository_keys(&owner, &repo, &release)
+        .await?;
+    let signed_by = sign_packages().then(|| keys.fingerprint());
+    let mut repo = Repository::from_release(owner, repo, &state, release, signed_by).await;
+
+    let tag = repo.client_tag(&[&distro, agent.as_str(), &arch, &file]);
+    if let Some(response) = tag.not_modified(&headers) {
+        return Ok(response);
+    }
+
     let packages = repo.select_package_apt(&distro, agent.as_str()).await?;
 
-    let index = AptIndices::new(&packages)?;
+    let index = keys
+        .blocking(move |keys| apt_indices(keys, &packages))
+        .await?;
     repo.save_package_metadata().await;
 
-    let Ok(arch) = arch.parse::<Arch>() else {
-        return Err(anyhow!("Unknown architecture: {arch}").into());
+    // Multi-arch clients also request the indices of their foreign architectures
+    // (e.g. `i386`). Serve an empty index when nothing is published for them.
+    let index = match arch.parse::<Arch>() {
+        Ok(arch) => index.get_package_file(&arch, &file),
+        Err(_) => compress_index(&file, b""),
     };
 
-    match file.as_str() {
-        "Packages" => Ok(index.get_package_index(&arch).as_bytes().to_owned()),
-        "Packages.gz" => Ok(gzip_compression(index.get_package_index(&arch).as_bytes())),
-        file => Err(anyhow!("Unknown file requested: {file}").into()),
+    let index = match file.as_str() {
+        "Packages" | "Packages.gz" | "Packages.xz" | "Packages.zst" => {
+            index.ok_or_else(|| anyhow!("Unknown file requested: {file}"))?
+        }
+        file => return Err(anyhow!("Unknown file requested: {file}").into()),
+    };
+
+    let index = Index::new(index, repo.last_modified()).tagged(&tag);
+    Ok(index.respond(&headers))
+}
+
+#[tracing::instrument(name = "Debian Contents file", skip_all, fields(agent = agent.as_str()))]
+async fn contents_file(
+    State(state): State<AppState>,
+    Path((distro, owner, repo, file)): Path<(String, Owner, Repo, String)>,
+    TypedHeader(agent): TypedHeader<UserAgent>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let release = Repository::latest_release(&state, &owner, &repo).await?;
+    let keys = state
+        .released_repository_keys(&owner, &repo, &release)
+        .await?;
+    let signed_by = sign_packages().then(|| keys.fingerprint());
+    let mut repo = Repository::from_release(owner, repo, &state, release, signed_by).await;
+
+    let tag = repo.client_tag(&[&distro, agent.as_str(), &file]);
+    if let Some(response) = tag.not_modified(&headers) {
+        return Ok(response);
     }
+
+    let packages = repo.select_package_apt(&distro, agent.as_str()).await?;
+
+    let index = keys
+        .blocking(move |keys| apt_indices(keys, &packages))
+        .await?;
+    repo.save_package_metadata().await;
+
+    let Some(name) = file.strip_prefix("Contents-") else {
+        return Err(anyhow!("Unknown file requested: {file}").into());
+    };
+    let arch = Compression::split(name).map_or(name, |(arch, _)| arch);
+
+    let index = match arch.parse::<Arch>() {
+        Ok(arch) => index.get_contents_file(&arch, &file),
+        Err(_) => compress_index(&file, b""),
+    };
+
+    let index = index.ok_or_else(|| anyhow!("Unknown file requested: {file}"))?;
+
+    let index = Index::new(index, repo.last_modified()).tagged(&tag);
+    Ok(index.respond(&headers))
 }
 
-async fn empty_packages_file(
-    Path((_, _, _, file)): Path<(String, String, String, String)>,
-) -> Result<Vec<u8>, AppError> {
-    match file.as_str() {
-        "Packages" => Ok(Vec::new()),
-        "Packages.gz" => Ok(gzip_compression(&Vec::new())),
-        file => Err(anyhow!("Unknown file requested: {file}").into()),
+#[tracing::instrument(name = "Debian Translation file", skip_all, fields(agent = agent.as_str()))]
+async fn translation_file(
+    State(state): State<AppState>,
+    Path((distro, owner, repo, file)): Path<(String, Owner, Repo, String)>,
+    TypedHeader(agent): TypedHeader<UserAgent>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let release = Repository::latest_release(&state, &owner, &repo).await?;
+    let keys = state
+        .released_repository_keys(&owner, &repo, &release)
+        .await?;
+    let signed_by = sign_packages().then(|| keys.fingerprint());
+    let mut repo = Repository::from_release(owner, repo, &state, release, signed_by).await;
+
+    let tag = repo.client_tag(&[&distro, agent.as_str(), &file]);
+    if let Some(response) = tag.not_modified(&headers) {
+        return Ok(response);
     }
+
+    let packages = repo.select_package_apt(&distro, agent.as_str()).await?;
+
+    let index = keys
+        .blocking(move |keys| apt_indices(keys, &packages))
+        .await?;
+    repo.save_package_metadata().await;
+
+    let index = index
+        .get_translation_file(&file)
+        .ok_or_else(|| anyhow!("Unknown file requested: {file}"))?;
+
+    let index = Index::new(index, repo.last_modified()).tagged(&tag);
+    Ok(index.respond(&headers))
+}
+
+#[tracing::instrument(name = "Debian index file by hash", skip_all)]
+async fn index_file_by_hash(
+    State(state): State<AppState>,
+    Path((_, owner, repo, dir, hash)): Path<(String, Owner, Repo, String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let collection = state
+        .db()
+        .database("packhub")
+        .collection::<IndexFile>("indices");
+
+    let file = IndexFile::retrieve(&collection, &project(&owner, &repo), &dir, &hash)
+        .await?
+        .ok_or_else(|| anyhow!("No index found with the hash: {hash}"))?;
+    let modified = file.creation_date();
+
+    Ok(Index::new(file.data(), modified).respond(&headers))
+}
+
+async fn packages_file_by_hash(
+    state: State<AppState>,
+    Path((distro, owner, repo, arch, hash)): Path<(String, Owner, Repo, String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let dir = format!("main/binary-{arch}");
+    index_file_by_hash(state, Path((distro, owner, repo, dir, hash)), headers).await
+}
+
+async fn main_file_by_hash(
+    state: State<AppState>,
+    Path((distro, owner, repo, hash)): Path<(String, Owner, Repo, String)>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let dir = "main".to_owned();
+    index_file_by_hash(state, Path((distro, owner, repo, dir, hash)), headers).await
 }
 
+async fn i18n_file_by_hash(
+    state: State<AppState>,
+    Path((distro, owner, repo, hash)): Path<(String, Owner, Repo, String)>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let dir = "main/i18n".to_owned();
+    index_file_by_hash(state, Path((distro, owner, repo, dir, hash)), headers).await
+}
+
+/// Notes of the stored release of the package version in the `@CHANGEPATH@`, which is
+/// `component/prefix/source/source_version`.
+#[tracing::instrument(name = "Debian changelog", skip_all)]
+async fn changelog(
+    State(state): State<AppState>,
+    Path((_, owner, repo, path)): Path<(String, Owner, Repo, String)>,
+) -> Result<String, AppError> {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let repository = project(&owner, &repo);
+    let releases = state
+        .db()
+        .database("packhub")
+        .collection::<ReleaseMetadata>("releases");
+    let collection = state
+        .db()
+        .database("github")
+        .collection::<PackageMetadata>(&repository);
+
+    let releases = ReleaseMetadata::retrieve_all(&releases, &repository).await?;
+    let tags: Vec<&str> = releases.iter().map(ReleaseMetadata::tag).collect();
+
+    // The release is found by the tag of the stored package with the changelog
+    let tag = PackageMetadata::retrieve_released(&collection, &tags)
+        .await?
+        .into_iter()
+        .find(|metadata| {
+            from_str::<DebianPackage>(&metadata.metadata)
+                .is_ok_and(|package| package.get_changelog_name().as_deref() == Some(name))
+        })
+        .map(|metadata| metadata.tag)
+        .ok_or_else(|| anyhow!("No release found for the changelog: {path}"))?;
+    let release = releases
+        .iter()
+        .find(|release| release.tag() == tag)
+        .context(format!("Release isn't stored: {tag}"))?;
+
+    release
+        .notes()
+        .map(str::to_owned)
+        .ok_or_else(|| anyhow!("Release notes are not available").into())
+}
+
+/// Route of the packages, which are requested with their `Filename` in the indices.
+const POOL: &str = "/{distro}/github/{owner}/{repo}/pool/stable/{*path}";
+
 #[tracing::instrument(name = "Debian Package proxy", skip_all)]
 async fn pool(
-    Path((_, owner, repo, ver, file)): Path<(String, String, String, String, String)>,
-) -> Result<impl IntoResponse, AppError> {
-    let url = format!("https://github.com/{owner}/{repo}/releases/download/{ver}/{file}");
-    tracing::trace!("Proxying package from: {}", url);
-    let res = REQWEST
-        .get(url)
-        .send()
-        .await
-        .context("Error occurred while proxying package")?;
-    tracing::trace!("Proxying package respone: {}", res.status());
-    let stream = Body::from_stream(res.bytes_stream());
+    State(state): State<AppState>,
+    Path((_, owner, repo, asset)): Path<(String, Owner, Repo, AssetPath)>,
+    request: Request,
+) -> Result<Response, AppError> {
+    let AssetPath { tag: ver, file } = asset;
+    let keys = state.repository_keys(&owner, &repo).await?;
+    let signed_by = sign_packages().then(|| keys.fingerprint());
+    let (package, verification) =
+        served_package(&state, &owner, &repo, &ver, &file, signed_by.as_deref())
+            .await
+            .ok_or_else(|| anyhow!("Package isn't served by the repository: {ver}/{file}"))?;
+    let asset = Asset {
+        owner: &owner,
+        repo: &repo,
+        ver: &ver,
+        file: &file,
+    };
+    let expected = Expected {
+        size: package.size,
+        sha256: &package.sha256,
+    };
 
-    Ok(stream)
+    // Signed packages must be served with the same signatures as in the indices
+    let sign = package
+        .signature
+        .as_ref()
+        .map(|signature| |data: &[u8]| Ok([data, signature].concat()));
+
+    let response = proxy::serve_package(request, asset, expected, sign).await?;
+    Ok(proxy::with_verification(response, verification))
+}
+
+/// Build the indices of the packages, embedding the signatures of the `keys` into them
+/// if it is enabled.
+fn apt_indices(keys: &SigningKeys, packages: &[Package]) -> Result<AptIndices> {
+    if !sign_packages() {
+        return AptIndices::new(packages);
+    }
+
+    let user_id = keys.user_id();
+    let signer = DebSigner {
+        signer: &user_id,
+        detached_sign: &|data| keys.detached_sign_binary(data),
+        clearsign: &|data| keys.clearsign_metadata(data),
+    };
+
+    AptIndices::new_signed(packages, &signer)
+}
+
+/// Directory of the Release files, stored along with the index files they list.
+const RELEASE_DIR: &str = "dists/stable";
+
+/// Store the index files listed in the Release file, so that they can be requested by hash
+/// even after the Release file has changed.
+///
+/// The Release file is stored along with them, so that they are only stored again when it
+/// changes, which happens at least once per re-signing period.
+async fn store_index_files(
+    state: &AppState,
+    repository: &str,
+    index: &AptIndices,
+    release_file: &str,
+) {
+    let collection = state
+        .db()
+        .database("packhub")
+        .collection::<IndexFile>("indices");
+    let release = IndexFile::new(
+        repository.to_owned(),
+        RELEASE_DIR.to_owned(),
+        release_file.as_bytes(),
+    );
+
+    match release.is_stored(&collection).await {
+        Ok(true) => return,
+        Ok(false) => {}
+        Err(e) => {
+            tracing::error!("Failed to check the Release file of {repository}: {e}");
+            return;
+        }
+    }
+
+    // The Release file is stored last, as it marks the index files as stored
+    let files = index
+        .by_hash_files()
+        .into_iter()
+        .map(|(dir, data)| IndexFile::new(repository.to_owned(), dir.to_owned(), data))
+        .chain([release])
+        .collect::<Vec<_>>();
+
+    if let Err(e) = IndexFile::store(&collection, repository, &files, Utc::now() - VALIDITY).await {
+        tracing::error!("Failed to store the index files of {repository}: {e}");
+    }
+}
+
+/// Package served in the indices as `pool/stable/{ver}/{file}`, signed by the key with
+/// the `signed_by` fingerprint if any.
+async fn served_package(
+    state: &AppState,
+    owner: &Owner,
+    repo: &Repo,
+    ver: &Tag,
+    file: &str,
+    signed_by: Option<&str>,
+) -> Option<(DebianPackage, Verification)> {
+    let collection = state
+        .db()
+        .database("github")
+        .collection::<PackageMetadata>(&project(owner, repo));
+    let filename = format!("pool/stable/{ver}/{file}");
+
+    PackageMetadata::retrieve_all(&collection, file, signed_by)
+        .await
+        .into_iter()
+        .filter_map(|metadata| {
+            let verification = metadata.verification();
+            let package = from_str::<DebianPackage>(&metadata.data()).ok()?;
+            Some((package, verification))
+        })
+        .find(|(package, _)| package.filename == filename)
 }
 
 pub fn apt_routes() -> Router<AppState> {
@@ -101,16 +434,76 @@ pub fn apt_routes() -> Router<AppState> {
             "/{distro}/github/{owner}/{repo}/dists/stable/{file}",
             get(release_index),
         )
+        .route(
+            "/{distro}/github/{owner}/{repo}/dists/stable/main/{file}",
+            get(contents_file),
+        )
+        .route(
+            "/{distro}/github/{owner}/{repo}/dists/stable/main/by-hash/SHA256/{hash}",
+            get(main_file_by_hash),
+        )
+        .route(
+            "/{distro}/github/{owner}/{repo}/dists/stable/main/i18n/{file}",
+            get(translation_file),
+        )
+        .route(
+            "/{distro}/github/{owner}/{repo}/dists/stable/main/i18n/by-hash/SHA256/{hash}",
+            get(i18n_file_by_hash),
+        )
         .route(
             "/{distro}/github/{owner}/{repo}/dists/stable/main/binary-{arch}/{index}",
             get(packages_file),
         )
         .route(
-            "/{distro}/github/{owner}/{repo}/dists/stable/main/binary-all/{index}",
-            get(empty_packages_file),
+            "/{distro}/github/{owner}/{repo}/dists/stable/main/binary-{arch}/by-hash/SHA256/{hash}",
+            get(packages_file_by_hash),
         )
         .route(
-            "/{distro}/github/{owner}/{repo}/pool/stable/{ver}/{file}",
-            get(pool),
+            "/{distro}/github/{owner}/{repo}/changelogs/{*path}",
+            get(changelog),
         )
+        .route(POOL, get(pool))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::read;
+
+    use axum::{body::Body, http::StatusCode};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::package::tests::package_with_ver;
+
+    #[tokio::test]
+    async fn test_pool_of_nested_tags() {
+        let package = package_with_ver("packhub-hello_1.0.0_amd64.deb", "release/v1");
+        package.set_package_data(read("data/packhub-hello_1.0.0_amd64.deb").unwrap());
+        let deb = DebianPackage::from_package(&package).unwrap();
+        assert_eq!(
+            deb.filename,
+            "pool/stable/release/v1/packhub-hello_1.0.0_amd64.deb"
+        );
+
+        // The package is requested with its file name, whose tag has a slash
+        let router = Router::new().route(
+            POOL,
+            get(
+                |Path((_, _, _, asset)): Path<(String, Owner, Repo, AssetPath)>| async move {
+                    format!("{} {}", asset.tag, asset.file)
+                },
+            ),
+        );
+        let request =
+            axum::http::Request::get(format!("/debian/github/packhub/hello/{}", deb.filename))
+                .body(Body::empty())
+                .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "release/v1 packhub-hello_1.0.0_amd64.deb");
+    }
 }
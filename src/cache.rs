@@ -0,0 +1,290 @@
+//! Content addressed cache of the package files served by the proxies.
+//!
+//! The packages are stored on disk named by their SHA-256 hashes, as they are served, so
+//! that the pool requests don't need to reach GitHub. The least recently used packages are
+//! evicted when the cache exceeds its capacity.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::SystemTime,
+};
+
+use anyhow::{Result, bail};
+use tracing::{debug, warn};
+
+/// Default capacity of the cache in MiB.
+const DEFAULT_CAPACITY: u64 = 10 * 1024;
+
+/// Counter of the temporary files, which keeps their names unique within the process.
+static TEMPORARY: AtomicU64 = AtomicU64::new(0);
+
+/// The package cache, enabled by setting the `PACKHUB_CACHE_DIR` variable.
+///
+/// Its capacity is configured in MiB with the `PACKHUB_CACHE_SIZE` variable.
+pub static CACHE: LazyLock<Option<BlobCache>> = LazyLock::new(|| {
+    let dir = dotenvy::var("PACKHUB_CACHE_DIR").unwrap_or_default();
+    if dir.is_empty() {
+        return None;
+    }
+    let capacity = dotenvy::var("PACKHUB_CACHE_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(DEFAULT_CAPACITY);
+
+    BlobCache::open(dir, capacity * 1024 * 1024)
+        .inspect_err(|e| warn!("Package cache is disabled: {e}"))
+        .ok()
+});
+
+pub struct BlobCache {
+    dir: PathBuf,
+    capacity: u64,
+    index: Mutex<Index>,
+}
+
+/// Sizes of the cached packages and the order in which they were used.
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, Entry>,
+    size: u64,
+    clock: u64,
+}
+
+struct Entry {
+    size: u64,
+    used: u64,
+}
+
+impl BlobCache {
+    /// Open the cache in the `dir`, indexing the packages already stored in it.
+    pub fn open(dir: impl Into<PathBuf>, capacity: u64) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        // The packages are used in the order of their modification times
+        let mut blobs = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let metadata = entry.metadata()?;
+            // Packages which were being written when the server stopped
+            if name.ends_with(".tmp") && metadata.is_file() {
+                let _ = fs::remove_file(entry.path());
+                continue;
+            }
+            if !is_sha256(&name) || !metadata.is_file() {
+                continue;
+            }
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            blobs.push((modified, name, metadata.len()));
+        }
+        blobs.sort();
+
+        let cache = Self {
+            dir,
+            capacity,
+            index: Mutex::new(Index::default()),
+        };
+        {
+            let mut index = cache.index.lock().unwrap();
+            for (_, name, size) in blobs {
+                index.insert(name, size);
+            }
+            cache.evict(&mut index);
+        }
+
+        Ok(cache)
+    }
+
+    /// Path of the package with the `sha256` hash, if it is cached.
+    pub fn get(&self, sha256: &str) -> Option<PathBuf> {
+        let mut index = self.index.lock().unwrap();
+        index.touch(sha256)?;
+
+        Some(self.dir.join(sha256))
+    }
+
+    /// Whether a package of `size` bytes can be stored.
+    pub fn fits(&self, size: u64) -> bool {
+        size <= self.capacity
+    }
+
+    /// Unique path of a temporary file in the cache, which a package is written to before it
+    /// is stored, so that a partial package is never served.
+    pub fn temporary_path(&self) -> PathBuf {
+        let id = TEMPORARY.fetch_add(1, Ordering::Relaxed);
+        self.dir.join(format!("{}-{id}.tmp", std::process::id()))
+    }
+
+    /// Store the package `data` with the `sha256` hash.
+    pub fn insert(&self, sha256: &str, data: &[u8]) -> Result<()> {
+        if self.index.lock().unwrap().touch(sha256).is_some() {
+            return Ok(());
+        }
+
+        let temporary = self.temporary_path();
+        fs::write(&temporary, data)?;
+        self.insert_file(sha256, &temporary, data.len() as u64)
+    }
+
+    /// Store the package written to the `temporary` file with the `sha256` hash.
+    ///
+    /// The temporary file is moved into the cache, or removed when the package isn't stored.
+    pub fn insert_file(&self, sha256: &str, temporary: &Path, size: u64) -> Result<()> {
+        let result = self.rename(sha256, temporary, size);
+        if temporary.exists() {
+            let _ = fs::remove_file(temporary);
+        }
+
+        result
+    }
+
+    fn rename(&self, sha256: &str, temporary: &Path, size: u64) -> Result<()> {
+        if !is_sha256(sha256) {
+            bail!("Invalid SHA-256 hash: {sha256}");
+        }
+        if self.index.lock().unwrap().touch(sha256).is_some() {
+            return Ok(());
+        }
+        if !self.fits(size) {
+            bail!("Package is larger than the cache: {sha256}");
+        }
+
+        fs::rename(temporary, self.dir.join(sha256))?;
+        debug!("Cached package: {sha256}");
+
+        let mut index = self.index.lock().unwrap();
+        index.insert(sha256.to_owned(), size);
+        self.evict(&mut index);
+
+        Ok(())
+    }
+
+    /// Remove the least recently used packages until the cache fits in its capacity.
+    fn evict(&self, index: &mut Index) {
+        while index.size > self.capacity {
+            let Some(sha256) = index.least_recently_used() else {
+                break;
+            };
+            index.remove(&sha256);
+            if let Err(e) = fs::remove_file(self.dir.join(&sha256)) {
+                warn!("Failed to evict the cached package {sha256}: {e}");
+            }
+            debug!("Evicted cached package: {sha256}");
+        }
+    }
+}
+
+impl Index {
+    fn insert(&mut self, sha256: String, size: u64) {
+        self.clock += 1;
+        let entry = Entry {
+            size,
+            used: self.clock,
+        };
+        if let Some(previous) = self.entries.insert(sha256, entry) {
+            self.size -= previous.size;
+        }
+        self.size += size;
+    }
+
+    fn touch(&mut self, sha256: &str) -> Option<()> {
+        let entry = self.entries.get_mut(sha256)?;
+        self.clock += 1;
+        entry.used = self.clock;
+
+        Some(())
+    }
+
+    fn remove(&mut self, sha256: &str) {
+        if let Some(entry) = self.entries.remove(sha256) {
+            self.size -= entry.size;
+        }
+    }
+
+    fn least_recently_used(&self) -> Option<String> {
+        self.entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.used)
+            .map(|(sha256, _)| sha256.clone())
+    }
+}
+
+/// Store the package in the cache, if it is enabled.
+pub fn store(sha256: &str, data: &[u8]) {
+    if let Some(cache) = CACHE.as_ref()
+        && let Err(e) = cache.insert(sha256, data)
+    {
+        warn!("Failed to cache the package {sha256}: {e}");
+    }
+}
+
+/// Path of the cached package, if the cache is enabled and has it.
+pub fn cached(sha256: &str) -> Option<PathBuf> {
+    CACHE.as_ref()?.get(sha256)
+}
+
+/// Lowercase hex SHA-256 hashes name the packages, which also keeps them inside the cache.
+fn is_sha256(name: &str) -> bool {
+    name.len() == 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::Sha256;
+
+    use super::*;
+    use crate::utils::hashsum;
+
+    #[test]
+    fn test_blob_cache() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("packhub-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let packages: Vec<_> = (0..3u8).map(|i| vec![i; 10]).collect();
+        let hashes: Vec<_> = packages.iter().map(|p| hashsum::<Sha256>(p)).collect();
+
+        let cache = BlobCache::open(&dir, 25)?;
+        cache.insert(&hashes[0], &packages[0])?;
+        cache.insert(&hashes[1], &packages[1])?;
+        assert_eq!(fs::read(cache.get(&hashes[0]).unwrap())?, packages[0]);
+
+        // The second package is the least recently used one
+        cache.insert(&hashes[2], &packages[2])?;
+        assert!(cache.get(&hashes[1]).is_none());
+        assert!(!dir.join(&hashes[1]).exists());
+        assert!(cache.get(&hashes[0]).is_some());
+        assert!(cache.get(&hashes[2]).is_some());
+
+        assert!(cache.insert("../../etc/passwd", b"data").is_err());
+        assert_ne!(cache.temporary_path(), cache.temporary_path());
+        assert!(
+            cache
+                .insert(&hashsum::<Sha256>(&[0; 30]), &[0; 30])
+                .is_err()
+        );
+
+        // The rejected packages don't leave their temporary files
+        assert_eq!(fs::read_dir(&dir)?.count(), 2);
+
+        // The stored packages are indexed when the cache is opened again
+        fs::write(dir.join("1-0.tmp"), b"partial")?;
+        let cache = BlobCache::open(&dir, 25)?;
+        assert!(cache.get(&hashes[0]).is_some());
+        assert!(cache.get(&hashes[2]).is_some());
+        assert!(!dir.join("1-0.tmp").exists());
+
+        fs::remove_dir_all(dir)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+//! Conditional requests of the index files.
+//!
+//! The indices are served with an `ETag` of their content and the `Last-Modified` date of the
+//! release, so that the clients and the caches in front of PackHub can revalidate them instead
+//! of downloading them again.
+//!
+//! The indices selected for the client are tagged by the inputs they are built from instead,
+//! so that the unchanged indices don't need to be built to answer the conditional requests.
+
+use std::time::{Duration, SystemTime};
+
+use axum::{
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use axum_extra::headers::{
+    CacheControl, ETag, HeaderMapExt, IfModifiedSince, IfNoneMatch, LastModified,
+};
+use chrono::{DateTime, Utc};
+use sha2::Sha256;
+
+use crate::utils::hashsum;
+
+/// Default duration in seconds for which the caches can serve the indices without revalidating.
+const DEFAULT_MAX_AGE: u64 = 300;
+
+/// Index file along with its validators.
+pub struct Index {
+    data: Vec<u8>,
+    etag: ETag,
+    last_modified: SystemTime,
+    /// Whether the index depends on the `User-Agent` of the client.
+    vary: bool,
+}
+
+impl Index {
+    /// Index with the `data` content, last modified at `last_modified`.
+    pub fn new(data: Vec<u8>, last_modified: DateTime<Utc>) -> Self {
+        let etag = entity_tag(&data, false);
+
+        Self {
+            data,
+            etag,
+            last_modified: last_modified.into(),
+            vary: false,
+        }
+    }
+
+    /// Signature of the `signed` content.
+    ///
+    /// The signatures aren't reproducible, so they are weakly validated by the signed content.
+    pub fn signature(data: Vec<u8>, signed: &[u8], last_modified: DateTime<Utc>) -> Self {
+        Self {
+            data,
+            etag: entity_tag(signed, true),
+            last_modified: last_modified.into(),
+            vary: false,
+        }
+    }
+
+    /// Index selected for the client, validated by the `tag` of the inputs it was built from.
+    pub fn tagged(self, tag: &ClientTag) -> Self {
+        Self {
+            etag: tag.etag.clone(),
+            vary: true,
+            ..self
+        }
+    }
+
+    /// Respond with the index, or with `304 Not Modified` when the client already has it.
+    ///
+    /// As in RFC 9110, `If-Modified-Since` is only evaluated without `If-None-Match`.
+    pub fn respond(self, headers: &HeaderMap) -> Response {
+        let modified = match headers.typed_get::<IfNoneMatch>() {
+            Some(if_none_match) => if_none_match.precondition_passes(&self.etag),
+            None => headers
+                .typed_get::<IfModifiedSince>()
+                .is_none_or(|since| since.is_modified(self.last_modified)),
+        };
+
+        let mut response = if modified {
+            self.data.into_response()
+        } else {
+            StatusCode::NOT_MODIFIED.into_response()
+        };
+
+        let headers = response.headers_mut();
+        headers.typed_insert(LastModified::from(self.last_modified));
+        insert_validators(headers, self.etag, self.vary);
+
+        response
+    }
+}
+
+/// Entity tag of an index selected for the client, computed from the release and the headers
+/// used to detect the client before the index is built.
+///
+/// The indices are built again for the same inputs, so they are only weakly validated.
+pub struct ClientTag {
+    etag: ETag,
+}
+
+impl ClientTag {
+    pub fn new<S: AsRef<str>>(inputs: &[S]) -> Self {
+        let inputs: Vec<&str> = inputs.iter().map(AsRef::as_ref).collect();
+
+        Self {
+            etag: entity_tag(inputs.join("\0").as_bytes(), true),
+        }
+    }
+
+    /// Respond with `304 Not Modified` when the client already has the index with the tag.
+    ///
+    /// `None` is returned when the index must be built, which then evaluates the
+    /// `If-Modified-Since` header of the requests without `If-None-Match`.
+    pub fn not_modified(&self, headers: &HeaderMap) -> Option<Response> {
+        let if_none_match = headers.typed_get::<IfNoneMatch>()?;
+        if if_none_match.precondition_passes(&self.etag) {
+            return None;
+        }
+
+        let mut response = StatusCode::NOT_MODIFIED.into_response();
+        insert_validators(response.headers_mut(), self.etag.clone(), true);
+
+        Some(response)
+    }
+}
+
+/// Insert the `etag` and the caching headers of the indices.
+///
+/// The indices that `vary` with the client are only shared by the caches for the same
+/// `User-Agent`, as the distribution and the architecture are detected from it.
+fn insert_validators(headers: &mut HeaderMap, etag: ETag, vary: bool) {
+    headers.typed_insert(etag);
+    headers.typed_insert(
+        CacheControl::new()
+            .with_public()
+            .with_max_age(Duration::from_secs(max_age())),
+    );
+    if vary {
+        headers.insert(header::VARY, HeaderValue::from_static("user-agent"));
+    }
+}
+
+/// Duration in seconds for which the caches can serve the indices without revalidating.
+///
+/// It can be configured with the `PACKHUB_INDEX_MAX_AGE` variable.
+fn max_age() -> u64 {
+    dotenvy::var("PACKHUB_INDEX_MAX_AGE")
+        .ok()
+        .and_then(|age| age.parse().ok())
+        .unwrap_or(DEFAULT_MAX_AGE)
+}
+
+fn entity_tag(content: &[u8], weak: bool) -> ETag {
+    let hash = hashsum::<Sha256>(content);
+    let prefix = if weak { "W/" } else { "" };
+
+    format!("{prefix}\"{}\"", &hash[..32])
+        .parse()
+        .expect("Hex digests are valid entity tags")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(name: header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_conditional_requests() {
+        let date = DateTime::parse_from_rfc2822("Wed, 8 Nov 2023 16:40:12 +0000")
+            .unwrap()
+            .into();
+        let index = || Index::new(b"Package: openbangla-keyboard".to_vec(), date);
+
+        let response = index().respond(&HeaderMap::new());
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        assert_eq!(
+            response.headers()[header::LAST_MODIFIED],
+            "Wed, 08 Nov 2023 16:40:12 GMT"
+        );
+        assert_eq!(
+            response.headers()[header::CACHE_CONTROL],
+            "public, max-age=300"
+        );
+
+        let response = index().respond(&request(header::IF_NONE_MATCH, &etag));
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+
+        let response = index().respond(&request(header::IF_NONE_MATCH, "\"outdated\""));
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let since = request(header::IF_MODIFIED_SINCE, "Wed, 08 Nov 2023 16:40:12 GMT");
+        assert_eq!(index().respond(&since).status(), StatusCode::NOT_MODIFIED);
+        let since = request(header::IF_MODIFIED_SINCE, "Wed, 08 Nov 2023 16:40:11 GMT");
+        assert_eq!(index().respond(&since).status(), StatusCode::OK);
+
+        // The signatures are validated by the signed content
+        let signature = Index::signature(b"signature".to_vec(), b"Release", date);
+        let response = signature.respond(&HeaderMap::new());
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        assert!(etag.starts_with("W/"));
+        let signature = Index::signature(b"other signature".to_vec(), b"Release", date);
+        let response = signature.respond(&request(header::IF_NONE_MATCH, &etag));
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(!response.headers().contains_key(header::VARY));
+    }
+
+    #[test]
+    fn test_client_tags() {
+        let date = DateTime::UNIX_EPOCH;
+        let tag = ClientTag::new(&["v2.0.0", "Debian APT-HTTP/1.3 (2.6.1)", "Packages"]);
+        assert!(tag.not_modified(&HeaderMap::new()).is_none());
+
+        let response = Index::new(b"Package: openbangla-keyboard".to_vec(), date)
+            .tagged(&tag)
+            .respond(&HeaderMap::new());
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::VARY], "user-agent");
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        assert!(etag.starts_with("W/"));
+
+        // The index isn't built again when the client already has it
+        let response = tag
+            .not_modified(&request(header::IF_NONE_MATCH, &etag))
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+        assert_eq!(response.headers()[header::VARY], "user-agent");
+
+        // Other clients or releases have other tags
+        let other = ClientTag::new(&["v2.0.0", "Debian APT-HTTP/1.3 (1.6.12)", "Packages"]);
+        assert!(
+            other
+                .not_modified(&request(header::IF_NONE_MATCH, &etag))
+                .is_none()
+        );
+        let other = ClientTag::new(&["v2.0.1", "Debian APT-HTTP/1.3 (2.6.1)", "Packages"]);
+        assert!(
+            other
+                .not_modified(&request(header::IF_NONE_MATCH, &etag))
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! Configuration of a repository, read from the `.packhub.toml` file of the repository.
+
+use std::{fmt::Display, sync::LazyLock};
+
+use octocrab::Octocrab;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+const CONFIG_FILE: &str = ".packhub.toml";
+
+static SECURITY: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\b(security|vulnerabilit(y|ies)|CVE-\d+-\d+)").unwrap());
+static BUGFIX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\b(fix(es|ed)?|bugs?|regressions?|crash(es)?)\b").unwrap());
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RepositoryConfig {
+    #[serde(default)]
+    pub updateinfo: UpdateInfo,
+    #[serde(default)]
+    pub signatures: Signatures,
+}
+
+/// Configuration of the advisories published in the `updateinfo.xml` of RPM repositories.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UpdateInfo {
+    /// Type of the advisories. It is detected from the release notes when not specified.
+    #[serde(rename = "type")]
+    pub advisory: Option<AdvisoryType>,
+}
+
+/// Configuration of the verification of the assets with the `.asc` or `.sig` signatures
+/// published along with them.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Signatures {
+    /// ASCII armored public key of the maintainer.
+    pub key: Option<String>,
+    /// Path of the public key of the maintainer in the repository.
+    pub key_file: Option<String>,
+    /// Skip the assets which aren't signed.
+    #[serde(default)]
+    pub required: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdvisoryType {
+    Security,
+    Bugfix,
+    Enhancement,
+}
+
+impl RepositoryConfig {
+    /// Retrieve the configuration of the repository at the `tag`.
+    ///
+    /// The default configuration is returned if the repository doesn't have any.
+    pub async fn from_github(github: &Octocrab, owner: &str, repo: &str, tag: &str) -> Self {
+        let Some(content) = read_file(github, owner, repo, tag, CONFIG_FILE).await else {
+            debug!("No {CONFIG_FILE} found in {owner}/{repo} at {tag}");
+            return Self::default();
+        };
+
+        toml::from_str(&content).unwrap_or_else(|e| {
+            warn!("Invalid {CONFIG_FILE} in {owner}/{repo} at {tag}: {e}");
+            Self::default()
+        })
+    }
+
+    /// Retrieve the ASCII armored public key of the maintainer, declared in the
+    /// configuration or read from the `key_file` of the repository at the `tag`.
+    pub async fn maintainer_key(
+        &self,
+        github: &Octocrab,
+        owner: &str,
+        repo: &str,
+        tag: &str,
+    ) -> Option<String> {
+        match (&self.signatures.key, &self.signatures.key_file) {
+            (Some(key), _) => Some(key.clone()),
+            (None, Some(path)) => read_file(github, owner, repo, tag, path).await,
+            (None, None) => None,
+        }
+    }
+
+    /// Type of the advisory of a release with the `notes`.
+    ///
+    /// The type configured in the repository takes precedence over the one
+    /// detected from the release notes.
+    pub fn advisory_type(&self, notes: Option<&str>) -> AdvisoryType {
+        self.updateinfo
+            .advisory
+            .unwrap_or_else(|| AdvisoryType::detect(notes.unwrap_or_default()))
+    }
+}
+
+/// Read the content of the file at `path` in the repository at the `tag`.
+async fn read_file(
+    github: &Octocrab,
+    owner: &str,
+    repo: &str,
+    tag: &str,
+    path: &str,
+) -> Option<String> {
+    let content = github
+        .repos(owner, repo)
+        .get_content()
+        .path(path)
+        .r#ref(tag)
+        .send()
+        .await;
+
+    content
+        .ok()
+        .and_then(|mut content| content.take_items().into_iter().next())
+        .and_then(|item| item.decoded_content())
+}
+
+impl AdvisoryType {
+    /// Detect the type of the advisory from the keywords of the release notes.
+    pub fn detect(notes: &str) -> Self {
+        if SECURITY.is_match(notes) {
+            AdvisoryType::Security
+        } else if BUGFIX.is_match(notes) {
+            AdvisoryType::Bugfix
+        } else {
+            AdvisoryType::Enhancement
+        }
+    }
+}
+
+impl Display for AdvisoryType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self {
+            AdvisoryType::Security => "security",
+            AdvisoryType::Bugfix => "bugfix",
+            AdvisoryType::Enhancement => "enhancement",
+        };
+        write!(f, "{kind}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_parsing() {
+        let config: RepositoryConfig = toml::from_str("[updateinfo]\ntype = \"security\"").unwrap();
+        assert_eq!(config.updateinfo.advisory, Some(AdvisoryType::Security));
+
+        let config: RepositoryConfig = toml::from_str("").unwrap();
+        assert_eq!(config.updateinfo.advisory, None);
+        assert!(config.signatures.key.is_none());
+        assert!(!config.signatures.required);
+
+        let config: RepositoryConfig =
+            toml::from_str("[signatures]\nkey_file = \"KEYS.asc\"\nrequired = true").unwrap();
+        assert_eq!(config.signatures.key_file.as_deref(), Some("KEYS.asc"));
+        assert!(config.signatures.required);
+    }
+
+    #[test]
+    fn test_advisory_detection() {
+        assert_eq!(
+            AdvisoryType::detect("Fixes CVE-2024-3094 in the bundled library"),
+            AdvisoryType::Security
+        );
+        assert_eq!(
+            AdvisoryType::detect("## Security\n- Sanitize the input"),
+            AdvisoryType::Security
+        );
+        assert_eq!(
+            AdvisoryType::detect("- Fixed a crash on startup"),
+            AdvisoryType::Bugfix
+        );
+        assert_eq!(
+            AdvisoryType::detect("- Added a new layout\n- Prefix support"),
+            AdvisoryType::Enhancement
+        );
+    }
+}
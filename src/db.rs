@@ -1,8 +1,29 @@
-use bson::doc;
+use anyhow::{Context, Result};
+use bson::{Binary, doc, spec::BinarySubtype, to_document};
 use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+use mongodb::options::{Collation, CollationStrength, ReturnDocument};
+use octocrab::models::repos::Release;
+use sequoia_openpgp::{Cert, parse::Parse, serialize::SerializeInto};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::warn;
 
-use crate::package::{Data, Package};
+use crate::{
+    config::{AdvisoryType, RepositoryConfig},
+    package::{Data, Package, Verification},
+    utils::hashsum,
+};
+
+/// Version of the format of the package metadata.
+///
+/// It is increased when the metadata gains information which can't be defaulted, so that
+/// the metadata stored with an older version is created again from the package.
+///
+/// - `1`: File lists of the Debian packages and weak dependencies of the RPM packages.
+/// - `2`: All the changelog entries of the RPM packages.
+/// - `3`: Key which re-signed the packages.
+const METADATA_VERSION: u32 = 3;
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub struct PackageMetadata {
@@ -10,13 +31,32 @@ pub struct PackageMetadata {
     #[serde(with = "mongodb::bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     created_at: DateTime<Utc>,
     metadata: String,
+    #[serde(default)]
+    verification: Verification,
+    #[serde(default)]
+    version: u32,
+    /// Fingerprint of the key which re-signed the package, if it was re-signed.
+    #[serde(default)]
+    signed_by: Option<String>,
+    /// Tag of the release of the package.
+    #[serde(default)]
+    tag: Option<String>,
+}
+
+/// Metadata of a package of a previous release, without the other stored fields.
+#[derive(Deserialize)]
+pub struct ReleasedMetadata {
+    pub tag: String,
+    pub metadata: String,
 }
 
 impl PackageMetadata {
     /// Create a new `PackageMetadata` from metadata of a `Package`.
     ///
+    /// The package is re-signed by the key with the `signed_by` fingerprint, if any.
+    ///
     /// `None` is returned if the package metadata is not available.
-    pub fn from_package(package: &Package) -> Option<Self> {
+    pub fn from_package(package: &Package, signed_by: Option<&str>) -> Option<Self> {
         let Data::Metadata(metadata) = package.data() else {
             return None;
         };
@@ -25,26 +65,411 @@ impl PackageMetadata {
             name: package.file_name().to_owned(),
             created_at: *package.creation_date(),
             metadata,
+            verification: package.verification(),
+            version: METADATA_VERSION,
+            signed_by: signed_by.map(str::to_owned),
+            tag: Some(package.version().to_owned()),
         })
     }
 
+    /// Record the package whose signature is invalid, so that it isn't downloaded again.
+    ///
+    /// It has no metadata, as the package is never served.
+    pub fn invalid(package: &Package, signed_by: Option<&str>) -> Self {
+        Self {
+            name: package.file_name().to_owned(),
+            created_at: *package.creation_date(),
+            metadata: String::new(),
+            verification: Verification::Invalid,
+            version: METADATA_VERSION,
+            signed_by: signed_by.map(str::to_owned),
+            tag: Some(package.version().to_owned()),
+        }
+    }
+
+    /// Retrieve the metadata of the package re-signed by the key with the `signed_by`
+    /// fingerprint, or of the original package when `None`.
+    ///
+    /// Metadata stored with an older version isn't retrieved.
     pub async fn retrieve_from(
         collection: &mongodb::Collection<PackageMetadata>,
         package: &Package,
+        signed_by: Option<&str>,
     ) -> Option<Self> {
         collection
-            .find_one(doc! { "name": package.file_name(), "created_at": package.creation_date() })
+            .find_one(doc! {
+                "name": package.file_name(),
+                "created_at": package.creation_date(),
+                "version": METADATA_VERSION,
+                "signed_by": signed_by,
+            })
+            .await
+            .unwrap()
+    }
+
+    /// Store the metadata, replacing the one stored for the package with the same key before.
+    pub async fn store(&self, collection: &mongodb::Collection<PackageMetadata>) -> Result<()> {
+        collection
+            .replace_one(
+                doc! {
+                    "name": &self.name,
+                    "created_at": self.created_at,
+                    "signed_by": &self.signed_by,
+                },
+                self,
+            )
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Retrieve the metadata of all the packages named `name` re-signed by the key with the
+    /// `signed_by` fingerprint, the most recent first.
+    ///
+    /// Packages of different releases can have the same name.
+    pub async fn retrieve_all(
+        collection: &mongodb::Collection<PackageMetadata>,
+        name: &str,
+        signed_by: Option<&str>,
+    ) -> Vec<Self> {
+        collection
+            .find(doc! { "name": name, "version": METADATA_VERSION, "signed_by": signed_by })
+            .sort(doc! { "created_at": -1 })
+            .await
+            .unwrap()
+            .try_collect()
             .await
             .unwrap()
     }
 
+    /// Retrieve the metadata of the packages of the releases tagged `tags`, the most recent first.
+    ///
+    /// The metadata stored before the tags were recorded isn't retrieved.
+    pub async fn retrieve_released(
+        collection: &mongodb::Collection<PackageMetadata>,
+        tags: &[&str],
+    ) -> Result<Vec<ReleasedMetadata>> {
+        Ok(collection
+            .clone_with_type::<ReleasedMetadata>()
+            .find(doc! {
+                "tag": { "$in": tags },
+                "version": METADATA_VERSION,
+                "verification": { "$ne": "invalid" },
+            })
+            .projection(doc! { "_id": 0, "tag": 1, "metadata": 1 })
+            .sort(doc! { "created_at": -1 })
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    /// Status of the verification of the package when its metadata was created.
+    pub fn verification(&self) -> Verification {
+        self.verification
+    }
+
     pub fn data(self) -> String {
         self.metadata
     }
 }
 
+/// Signing key of a repository, stored ASCII armored with its encrypted secret keys.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RepositoryKey {
+    repository: String,
+    #[serde(with = "mongodb::bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    created_at: DateTime<Utc>,
+    key: String,
+}
+
+impl RepositoryKey {
+    pub fn new(repository: String, cert: &Cert) -> Result<Self> {
+        let key = String::from_utf8(cert.as_tsk().armored().to_vec()?)?;
+
+        Ok(Self {
+            repository,
+            created_at: Utc::now(),
+            key,
+        })
+    }
+
+    /// Retrieve the key of the `repository`.
+    ///
+    /// The name is matched regardless of the case, as the keys were stored with the case of
+    /// the first request before the names were normalized. The oldest key is kept.
+    pub async fn retrieve(
+        collection: &mongodb::Collection<RepositoryKey>,
+        repository: &str,
+    ) -> Result<Option<Self>> {
+        Ok(collection
+            .find_one(doc! { "repository": repository })
+            .collation(case_insensitive())
+            .sort(doc! { "created_at": 1 })
+            .await?)
+    }
+
+    /// Store the key unless the repository already has one.
+    ///
+    /// The stored key of the repository is returned.
+    pub async fn insert_if_missing(
+        collection: &mongodb::Collection<RepositoryKey>,
+        key: &Self,
+    ) -> Result<Self> {
+        collection
+            .find_one_and_update(
+                doc! { "repository": &key.repository },
+                doc! { "$setOnInsert": to_document(key)? },
+            )
+            .upsert(true)
+            .collation(case_insensitive())
+            .return_document(ReturnDocument::After)
+            .await?
+            .context("Repository key wasn't stored")
+    }
+
+    pub fn cert(&self) -> Result<Cert> {
+        Cert::from_bytes(self.key.as_bytes())
+    }
+}
+
+/// Comparison of the repository names regardless of their case.
+fn case_insensitive() -> Collation {
+    Collation::builder()
+        .locale("en")
+        .strength(CollationStrength::Secondary)
+        .build()
+}
+
+/// Index file of an apt repository, kept to serve the `by-hash` requests.
+///
+/// The clients can request the index files listed in a Release file after it has been
+/// replaced, so the index files of the recent Release files are kept along with the latest.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IndexFile {
+    repository: String,
+    /// Directory of the index file in the distribution (e.g. `main/binary-amd64`).
+    dir: String,
+    sha256: String,
+    data: Binary,
+    #[serde(with = "mongodb::bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    created_at: DateTime<Utc>,
+    /// Last time the index file was listed in a Release file.
+    #[serde(with = "mongodb::bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    listed_at: DateTime<Utc>,
+}
+
+impl IndexFile {
+    pub fn new(repository: String, dir: String, data: &[u8]) -> Self {
+        let now = Utc::now();
+
+        Self {
+            repository,
+            dir,
+            sha256: hashsum::<Sha256>(data),
+            data: Binary {
+                subtype: BinarySubtype::Generic,
+                bytes: data.to_vec(),
+            },
+            created_at: now,
+            listed_at: now,
+        }
+    }
+
+    /// Store the index files listed in the current Release file of the `repository`.
+    ///
+    /// The index files which haven't been listed since `expired` are removed, as no Release
+    /// file listing them is accepted by the clients anymore.
+    pub async fn store(
+        collection: &mongodb::Collection<IndexFile>,
+        repository: &str,
+        files: &[Self],
+        expired: DateTime<Utc>,
+    ) -> Result<()> {
+        for file in files {
+            collection
+                .update_one(
+                    doc! { "repository": &file.repository, "dir": &file.dir, "sha256": &file.sha256 },
+                    doc! {
+                        "$set": { "listed_at": file.listed_at },
+                        "$setOnInsert": { "data": &file.data, "created_at": file.created_at },
+                    },
+                )
+                .upsert(true)
+                .await?;
+        }
+
+        collection
+            .delete_many(doc! { "repository": repository, "listed_at": { "$lt": expired } })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Whether the same index file is already stored.
+    pub async fn is_stored(&self, collection: &mongodb::Collection<IndexFile>) -> Result<bool> {
+        let count = collection
+            .count_documents(
+                doc! { "repository": &self.repository, "dir": &self.dir, "sha256": &self.sha256 },
+            )
+            .limit(1)
+            .await?;
+
+        Ok(count > 0)
+    }
+
+    /// Retrieve the index file of the `repository` located in `dir` whose SHA256 checksum is `hash`.
+    pub async fn retrieve(
+        collection: &mongodb::Collection<IndexFile>,
+        repository: &str,
+        dir: &str,
+        hash: &str,
+    ) -> Result<Option<Self>> {
+        Ok(collection
+            .find_one(doc! { "repository": repository, "dir": dir, "sha256": hash })
+            .await?)
+    }
+
+    /// Date when the index file was first listed in a Release file.
+    pub fn creation_date(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    pub fn data(self) -> Vec<u8> {
+        self.data.bytes
+    }
+}
+
+/// Release of a repository, stored with the configuration of the repository at its tag.
+///
+/// The configuration is read from GitHub only the first time the release is seen, and the
+/// stored releases are published as the advisories of the RPM repositories.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReleaseMetadata {
+    repository: String,
+    tag: String,
+    title: Option<String>,
+    notes: Option<String>,
+    #[serde(with = "mongodb::bson::serde_helpers::chrono_datetime_as_bson_datetime_optional")]
+    published_at: Option<DateTime<Utc>>,
+    config: RepositoryConfig,
+    /// ASCII armored public key of the maintainer.
+    maintainer_key: Option<String>,
+}
+
+impl ReleaseMetadata {
+    pub fn new(
+        repository: String,
+        release: &Release,
+        config: RepositoryConfig,
+        maintainer_key: Option<String>,
+    ) -> Self {
+        Self {
+            repository,
+            tag: release.tag_name.clone(),
+            title: release.name.clone(),
+            notes: release.body.clone(),
+            published_at: release.published_at,
+            config,
+            maintainer_key,
+        }
+    }
+
+    /// Retrieve the release of the `repository` tagged `tag`.
+    pub async fn retrieve(
+        collection: &mongodb::Collection<ReleaseMetadata>,
+        repository: &str,
+        tag: &str,
+    ) -> Result<Option<Self>> {
+        Ok(collection
+            .find_one(doc! { "repository": repository, "tag": tag })
+            .await?)
+    }
+
+    /// Retrieve all the releases of the `repository`, the most recent first.
+    pub async fn retrieve_all(
+        collection: &mongodb::Collection<ReleaseMetadata>,
+        repository: &str,
+    ) -> Result<Vec<Self>> {
+        Ok(collection
+            .find(doc! { "repository": repository })
+            .sort(doc! { "published_at": -1 })
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    /// Store the release, replacing the one stored for the tag before.
+    pub async fn store(&self, collection: &mongodb::Collection<ReleaseMetadata>) -> Result<()> {
+        collection
+            .replace_one(
+                doc! { "repository": &self.repository, "tag": &self.tag },
+                self,
+            )
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Update the details of the release, which can be edited after its publication.
+    ///
+    /// Returns whether any of them changed.
+    pub fn update(&mut self, release: &Release) -> bool {
+        let changed = self.title != release.name
+            || self.notes != release.body
+            || self.published_at != release.published_at;
+
+        self.title = release.name.clone();
+        self.notes = release.body.clone();
+        self.published_at = release.published_at;
+
+        changed
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Title of the release. The tag is used when the release has no title.
+    pub fn title(&self) -> &str {
+        self.title.as_deref().unwrap_or(&self.tag)
+    }
+
+    pub fn notes(&self) -> Option<&str> {
+        self.notes.as_deref()
+    }
+
+    pub fn published_at(&self) -> Option<&DateTime<Utc>> {
+        self.published_at.as_ref()
+    }
+
+    pub fn config(&self) -> &RepositoryConfig {
+        &self.config
+    }
+
+    /// Public key of the maintainer declared in the configuration of the release.
+    pub fn maintainer_key(&self) -> Option<Cert> {
+        let key = self.maintainer_key.as_ref()?;
+
+        Cert::from_bytes(key.as_bytes())
+            .inspect_err(|e| {
+                warn!(
+                    "Invalid maintainer key in {} at {}: {e}",
+                    self.repository, self.tag
+                )
+            })
+            .ok()
+    }
+
+    /// Type of the advisory of the release.
+    pub fn advisory_type(&self) -> AdvisoryType {
+        self.config.advisory_type(self.notes())
+    }
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use std::fs::read;
 
     use super::*;
@@ -54,9 +479,13 @@ mod tests {
         testcontainers::{ContainerAsync, runners::AsyncRunner},
     };
 
-    use crate::apt::DebianPackage;
+    use crate::{
+        apt::DebianPackage,
+        package::tests::package_with_ver,
+        pgp::{email_domain, generate_repository_cert},
+    };
 
-    pub async fn setup_mongodb(container: &ContainerAsync<Mongo>) -> Client {
+    pub(crate) async fn setup_mongodb(container: &ContainerAsync<Mongo>) -> Client {
         let host = container.get_host().await.unwrap();
         let port = container.get_host_port_ipv4(27017).await.unwrap();
 
@@ -79,11 +508,11 @@ mod tests {
         let db = client.database("github");
         let collection = db.collection::<PackageMetadata>("test");
 
-        let metadata = PackageMetadata::from_package(&package).unwrap();
+        let metadata = PackageMetadata::from_package(&package, None).unwrap();
 
         collection.insert_one(&metadata).await.unwrap();
 
-        let retrieved = PackageMetadata::retrieve_from(&collection, &package)
+        let retrieved = PackageMetadata::retrieve_from(&collection, &package, None)
             .await
             .unwrap();
 
@@ -92,8 +521,174 @@ mod tests {
         let non_existent = Package::detect_package("OpenBangla-Keyboard_2.0.0-ubuntu20.04.deb", "2.0.0".to_owned(), "https://github.com/OpenBangla/OpenBangla-Keyboard/releases/download/2.0.0/OpenBangla-Keyboard_2.0.0-ubuntu20.04.deb".to_owned(), DateTime::parse_from_rfc3339("2024-07-10T00:00:00Z").unwrap().into()).unwrap();
 
         assert_eq!(
-            PackageMetadata::retrieve_from(&collection, &non_existent).await,
+            PackageMetadata::retrieve_from(&collection, &non_existent, None).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_outdated_metadata() {
+        let container = Mongo::default().start().await.unwrap();
+        let client = setup_mongodb(&container).await;
+        let collection = client
+            .database("github")
+            .collection::<PackageMetadata>("test");
+
+        let package = package_with_ver("packhub-hello_1.0.0_amd64.deb", "1.0.0");
+        package.set_package_data(read("data/packhub-hello_1.0.0_amd64.deb").unwrap());
+        let deb = DebianPackage::from_package(&package).unwrap();
+        assert!(!deb.files.is_empty());
+
+        // Metadata stored before the file list was a part of it
+        let mut outdated = PackageMetadata::from_package(&package, None).unwrap();
+        outdated.version = 0;
+        outdated.metadata = outdated.metadata.replace(r#""files":"#, r#""paths":"#);
+        outdated.store(&collection).await.unwrap();
+
+        assert_eq!(
+            PackageMetadata::retrieve_from(&collection, &package, None).await,
             None
         );
+        assert!(
+            PackageMetadata::retrieve_all(&collection, package.file_name(), None)
+                .await
+                .is_empty()
+        );
+
+        // The package is parsed again and its metadata replaces the outdated one
+        let metadata = PackageMetadata::from_package(&package, None).unwrap();
+        metadata.store(&collection).await.unwrap();
+
+        assert_eq!(
+            PackageMetadata::retrieve_from(&collection, &package, None).await,
+            Some(metadata)
+        );
+        assert_eq!(collection.count_documents(doc! {}).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_signed_metadata() {
+        let container = Mongo::default().start().await.unwrap();
+        let client = setup_mongodb(&container).await;
+        let collection = client
+            .database("github")
+            .collection::<PackageMetadata>("test");
+        let fingerprint = "C4A5D8B6E2F1A9C7D3E8B1F6A2C9D7E4B8F3A1C6";
+
+        let package = package_with_ver("packhub-hello_1.0.0_amd64.deb", "1.0.0");
+        package.set_package_data(read("data/packhub-hello_1.0.0_amd64.deb").unwrap());
+        DebianPackage::from_package(&package).unwrap();
+        let unsigned = PackageMetadata::from_package(&package, None).unwrap();
+        unsigned.store(&collection).await.unwrap();
+
+        // The metadata of the original package isn't used once the signing is turned on
+        assert_eq!(
+            PackageMetadata::retrieve_from(&collection, &package, Some(fingerprint)).await,
+            None
+        );
+        assert!(
+            PackageMetadata::retrieve_all(&collection, package.file_name(), Some(fingerprint))
+                .await
+                .is_empty()
+        );
+
+        // The metadata of the re-signed package is stored along with the original one
+        let signed = PackageMetadata::from_package(&package, Some(fingerprint)).unwrap();
+        signed.store(&collection).await.unwrap();
+
+        assert_eq!(
+            PackageMetadata::retrieve_from(&collection, &package, Some(fingerprint)).await,
+            Some(signed)
+        );
+        assert_eq!(
+            PackageMetadata::retrieve_from(&collection, &package, None).await,
+            Some(unsigned)
+        );
+        assert_eq!(collection.count_documents(doc! {}).await.unwrap(), 2);
+
+        // The packages of the previous releases are retrieved by their tags
+        let released = PackageMetadata::retrieve_released(&collection, &["1.0.0"])
+            .await
+            .unwrap();
+        assert_eq!(released.len(), 2);
+        assert!(released.iter().all(|metadata| metadata.tag == "1.0.0"));
+        assert!(
+            PackageMetadata::retrieve_released(&collection, &["2.0.0"])
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_index_files() {
+        let container = Mongo::default().start().await.unwrap();
+        let client = setup_mongodb(&container).await;
+        let collection = client
+            .database("packhub")
+            .collection::<IndexFile>("indices");
+        let repository = "openbangla/openbangla-keyboard";
+
+        let previous = IndexFile::new(repository.to_owned(), "main".to_owned(), b"previous");
+        let expired = previous.listed_at;
+        IndexFile::store(&collection, repository, &[previous], expired)
+            .await
+            .unwrap();
+
+        let current = IndexFile::new(repository.to_owned(), "main".to_owned(), b"current");
+        assert!(!current.is_stored(&collection).await.unwrap());
+        IndexFile::store(&collection, repository, &[current], expired)
+            .await
+            .unwrap();
+        let current = IndexFile::new(repository.to_owned(), "main".to_owned(), b"current");
+        assert!(current.is_stored(&collection).await.unwrap());
+
+        // The index files of the previous Release file are still served
+        let hash = hashsum::<Sha256>(b"previous");
+        let file = IndexFile::retrieve(&collection, repository, "main", &hash)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(file.data(), b"previous");
+        let file = IndexFile::retrieve(&collection, repository, "main/i18n", &hash)
+            .await
+            .unwrap();
+        assert!(file.is_none());
+
+        // Until they expire
+        let current = IndexFile::new(repository.to_owned(), "main".to_owned(), b"current");
+        let expired = current.listed_at;
+        IndexFile::store(&collection, repository, &[current], expired)
+            .await
+            .unwrap();
+        let file = IndexFile::retrieve(&collection, repository, "main", &hash)
+            .await
+            .unwrap();
+        assert!(file.is_none());
+
+        let hash = hashsum::<Sha256>(b"current");
+        let file = IndexFile::retrieve(&collection, repository, "main", &hash)
+            .await
+            .unwrap();
+        assert!(file.is_some());
+    }
+
+    #[test]
+    fn test_repository_key() {
+        let passphrase = "passphrase".into();
+        let cert = generate_repository_cert(&passphrase, "OpenBangla/OpenBangla-Keyboard").unwrap();
+
+        let key = RepositoryKey::new("OpenBangla/OpenBangla-Keyboard".to_owned(), &cert).unwrap();
+        let stored = key.cert().unwrap();
+
+        assert_eq!(stored, cert);
+        assert!(stored.is_tsk());
+        assert_eq!(
+            stored.userids().next().unwrap().userid().to_string(),
+            format!(
+                "PackHub (OpenBangla/OpenBangla-Keyboard) <sign@{}>",
+                email_domain()
+            )
+        );
     }
 }
@@ -10,9 +10,12 @@ static NAME_RE: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r"(?i)^([a-z0-9_.-]+?)(?:[-_](?:v?\d.*))").unwrap());
 
 // Regex to capture architecture
+// `all` is only matched as a separate word, as it is a common substring.
 static ARCH_RE: LazyLock<Regex> = LazyLock::new(|| {
-    Regex::new(r"(?i)(x86_64|amd64|aarch64|arm64|armhf|armv7|s390x|armv6l|ppc64le|riscv64)")
-        .unwrap()
+    Regex::new(
+        r"(?i)(x86_64|amd64|i386|i686|aarch64|arm64|armhf|armel|armv7hl|armv7l?|armv6hl|armv6l|s390x|ppc64le|ppc64el|ppc64|riscv64|mips64el|loongarch64|loong64|noarch)|[_.-](all)(?:[_.-]|$)",
+    )
+    .unwrap()
 });
 
 static DISTRO_PATTERNS: LazyLock<Vec<(Regex, Dist)>> = LazyLock::new(|| {
@@ -88,6 +91,7 @@ impl PackageInfo {
         if let Some(caps) = ARCH_RE.captures(filename) {
             architecture = caps
                 .get(1)
+                .or_else(|| caps.get(2))
                 .and_then(|m| m.as_str().to_lowercase().parse().ok());
         }
 
@@ -194,7 +198,7 @@ mod tests {
         let info = PackageInfo::parse_package("rustdesk-1.3.8-0.aarch64-suse.rpm");
         assert_eq!(info.name, Some("rustdesk".into()));
         assert_eq!(info.distro, Some(Dist::Tumbleweed));
-        assert_eq!(info.architecture, Some(Arch::Aarch64));
+        assert_eq!(info.architecture, Some(Arch::Arm64));
     }
 
     #[test]
@@ -203,6 +207,7 @@ mod tests {
         let result = PackageInfo::parse_package(filename);
         assert_eq!(result.name, Some("another-package".into()));
         assert_eq!(result.distro, Some(Dist::Tumbleweed));
+        assert_eq!(result.architecture, Some(Arch::All));
     }
 
     #[test]
@@ -233,13 +238,13 @@ mod tests {
         assert_eq!(info.architecture, Some(Arch::S390x));
 
         let info = PackageInfo::parse_package("fastfetch-linux-armv6l.deb");
-        assert_eq!(info.architecture, Some(Arch::Armhf));
+        assert_eq!(info.architecture, Some(Arch::Armv6));
 
         let info = PackageInfo::parse_package("fastfetch-linux-aarch64.deb");
-        assert_eq!(info.architecture, Some(Arch::Aarch64));
+        assert_eq!(info.architecture, Some(Arch::Arm64));
 
         let info = PackageInfo::parse_package("fastfetch-linux-armv7l.deb");
-        assert_eq!(info.architecture, Some(Arch::Armv7));
+        assert_eq!(info.architecture, Some(Arch::Armhf));
 
         let info = PackageInfo::parse_package("fastfetch-linux-ppc64le.deb");
         assert_eq!(info.architecture, Some(Arch::PPC64le));
@@ -249,5 +254,17 @@ mod tests {
 
         let info = PackageInfo::parse_package("fastfetch-linux-riscv64.deb");
         assert_eq!(info.architecture, Some(Arch::RiscV64));
+
+        let info = PackageInfo::parse_package("steam-launcher_1.0.0.81_i386.deb");
+        assert_eq!(info.architecture, Some(Arch::I386));
+
+        let info = PackageInfo::parse_package("foo-1.0-1.ppc64.rpm");
+        assert_eq!(info.architecture, Some(Arch::PPC64));
+
+        let info = PackageInfo::parse_package("fonts-smallcaps_1.0.2_all.deb");
+        assert_eq!(info.architecture, Some(Arch::All));
+
+        let info = PackageInfo::parse_package("installer-1.0.0.deb");
+        assert_eq!(info.architecture, None);
     }
 }
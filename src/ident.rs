@@ -0,0 +1,209 @@
+//! Identifiers of the GitHub repositories and their releases.
+//!
+//! They are validated when extracted from the request paths, as they end up in the URLs of
+//! GitHub, the names of the database collections and the setup scripts.
+
+use std::{fmt::Display, ops::Deref};
+
+use anyhow::{Error, bail};
+use serde::Deserialize;
+
+macro_rules! identifier {
+    ($(#[$doc:meta])* $name:ident, $validate:ident) => {
+        $(#[$doc])*
+        #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+        #[serde(try_from = "String")]
+        pub struct $name(String);
+
+        impl TryFrom<String> for $name {
+            type Error = Error;
+
+            fn try_from(value: String) -> Result<Self, Self::Error> {
+                $validate(&value)?;
+                Ok(Self(value))
+            }
+        }
+
+        impl Deref for $name {
+            type Target = str;
+
+            fn deref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+    };
+}
+
+identifier!(
+    /// Name of a GitHub user or organization.
+    Owner,
+    validate_owner
+);
+
+identifier!(
+    /// Name of a GitHub repository.
+    Repo,
+    validate_repo
+);
+
+identifier!(
+    /// Tag of a release.
+    Tag,
+    validate_tag
+);
+
+/// Path of a release asset in the repositories, `{tag}/{file}`.
+///
+/// The tags can have slashes, so the file is the last component of the path.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct AssetPath {
+    pub tag: Tag,
+    pub file: String,
+}
+
+impl TryFrom<String> for AssetPath {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let Some((tag, file)) = value.rsplit_once('/') else {
+            bail!("Invalid asset path: {value}");
+        };
+        if file.is_empty() {
+            bail!("Invalid asset path: {value}");
+        }
+
+        Ok(Self {
+            tag: Tag::try_from(tag.to_owned())?,
+            file: file.to_owned(),
+        })
+    }
+}
+
+/// Name of the `owner/repo` repository in the database.
+///
+/// GitHub names are case insensitive, so it is normalized to lowercase. The package metadata
+/// stored before in the collections named with the requested case isn't used anymore, it is
+/// created again from the packages and the old collections can be dropped. The keys of the
+/// repositories are looked up regardless of the case, so that they are kept.
+pub fn project(owner: &Owner, repo: &Repo) -> String {
+    format!("{owner}/{repo}").to_lowercase()
+}
+
+/// Alphanumeric characters or single hyphens, which can't begin or end the name.
+fn validate_owner(owner: &str) -> anyhow::Result<()> {
+    if owner.is_empty() || owner.len() > 39 {
+        bail!("Invalid owner length: {owner}");
+    }
+    if !owner.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        || owner.starts_with('-')
+        || owner.ends_with('-')
+        || owner.contains("--")
+    {
+        bail!("Invalid owner: {owner}");
+    }
+
+    Ok(())
+}
+
+/// Alphanumeric characters, `-`, `_` and `.`, except the `.` and `..` names.
+fn validate_repo(repo: &str) -> anyhow::Result<()> {
+    if repo.is_empty() || repo.len() > 100 {
+        bail!("Invalid repository length: {repo}");
+    }
+    if !repo
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        || repo == "."
+        || repo == ".."
+    {
+        bail!("Invalid repository: {repo}");
+    }
+
+    Ok(())
+}
+
+/// The rules of `git check-ref-format`, which the tags of the releases follow.
+///
+/// They rule out the path traversals, as no component can begin with a `.`.
+fn validate_tag(tag: &str) -> anyhow::Result<()> {
+    if tag.is_empty() || tag.len() > 255 {
+        bail!("Invalid tag length: {tag}");
+    }
+    if tag.chars().any(|c| {
+        c.is_ascii_control() || matches!(c, ' ' | '~' | '^' | ':' | '?' | '*' | '[' | '\\')
+    }) || tag
+        .split('/')
+        .any(|part| part.is_empty() || part.starts_with('.') || part.ends_with(".lock"))
+        || tag.ends_with('.')
+        || tag.contains("..")
+        || tag.contains("@{")
+        || tag == "@"
+    {
+        bail!("Invalid tag: {tag}");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid<T: TryFrom<String>>(value: &str) -> bool {
+        T::try_from(value.to_owned()).is_ok()
+    }
+
+    #[test]
+    fn test_identifiers() {
+        assert!(valid::<Owner>("OpenBangla"));
+        assert!(valid::<Owner>("mominul-2"));
+        assert!(!valid::<Owner>("-mominul"));
+        assert!(!valid::<Owner>("mominul--2"));
+        assert!(!valid::<Owner>("mominul;rm"));
+        assert!(!valid::<Owner>(&"a".repeat(40)));
+
+        assert!(valid::<Repo>("OpenBangla-Keyboard"));
+        assert!(valid::<Repo>("packhub.dev_site"));
+        assert!(!valid::<Repo>(".."));
+        assert!(!valid::<Repo>("repo$(id)"));
+        assert!(!valid::<Repo>("repo name"));
+        assert!(!valid::<Repo>(""));
+
+        assert!(valid::<Tag>("2.0.0"));
+        assert!(valid::<Tag>("v1.0.0-rc.1+build"));
+        assert!(valid::<Tag>("release/1.0"));
+        assert!(valid::<Tag>("v1.0@beta"));
+        assert!(valid::<Tag>("版本-1.0"));
+        assert!(valid::<Tag>("-rf"));
+        assert!(valid::<Tag>("1.0'"));
+        assert!(!valid::<Tag>("../../etc"));
+        assert!(!valid::<Tag>("release/.1.0"));
+        assert!(!valid::<Tag>("release//1.0"));
+        assert!(!valid::<Tag>("/1.0"));
+        assert!(!valid::<Tag>("1.0."));
+        assert!(!valid::<Tag>("1.0.lock"));
+        assert!(!valid::<Tag>("1.0@{1}"));
+        assert!(!valid::<Tag>("@"));
+        assert!(!valid::<Tag>("1.0 beta"));
+        assert!(!valid::<Tag>("1.0~1"));
+        assert!(!valid::<Tag>("1.0\n"));
+
+        let asset = AssetPath::try_from("release/v1/hello_1.0.0_amd64.deb".to_owned()).unwrap();
+        assert_eq!(&*asset.tag, "release/v1");
+        assert_eq!(asset.file, "hello_1.0.0_amd64.deb");
+        assert!(AssetPath::try_from("hello_1.0.0_amd64.deb".to_owned()).is_err());
+        assert!(AssetPath::try_from("v1/".to_owned()).is_err());
+        assert!(AssetPath::try_from("../v1/hello.deb".to_owned()).is_err());
+
+        let owner = Owner::try_from("OpenBangla".to_owned()).unwrap();
+        let repo = Repo::try_from("OpenBangla-Keyboard".to_owned()).unwrap();
+        assert_eq!(project(&owner, &repo), "openbangla/openbangla-keyboard");
+    }
+}
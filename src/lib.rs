@@ -14,16 +14,22 @@ use tracing::{Span, debug};
 use crate::state::AppState;
 
 mod apt;
+mod cache;
+mod conditional;
+mod config;
 mod db;
 mod detect;
 mod error;
+mod ident;
 mod package;
 pub mod pgp;
 mod platform;
+mod proxy;
 mod repository;
 mod rpm;
 mod script;
 mod selector;
+pub mod signer;
 pub mod state;
 mod utils;
 
@@ -46,6 +52,7 @@ pub fn app(state: AppState) -> Router {
         .route_service("/", ServeFile::new("pages/index.html"))
         .nest("/v1", v1())
         .nest("/sh", script::script_routes())
+        .merge(pgp::key_discovery())
         .nest_service("/assets", ServeDir::new("pages/assets"))
         .with_state(state)
         .layer(TraceLayer::new_for_http().on_response(
@@ -1,11 +1,186 @@
-use std::{env::args, net::SocketAddr};
-
+use std::{
+    fs,
+    io::{Read, Write, stdin, stdout},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
 use axum_server::tls_rustls::RustlsConfig;
+use clap::{Parser, Subcommand, ValueEnum};
 use dotenvy::{dotenv, var};
+use sequoia_openpgp::{KeyHandle, cert::CipherSuite, crypto::Password};
 use tracing::{Level, info};
 use tracing_subscriber::{filter::Targets, prelude::*};
 
-use packhub::{app, state::AppState};
+use packhub::{
+    app,
+    pgp::{self, Keyring},
+    signer::{self, KeySigner, Operation},
+    state::AppState,
+};
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Generate new signing keys before starting the server
+    #[arg(long)]
+    generate_keys: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the signing keys
+    Keys {
+        /// Path of the keys, instead of the `PACKHUB_KEY_PATH` variable
+        #[arg(long, global = true)]
+        key: Option<PathBuf>,
+
+        #[command(subcommand)]
+        command: KeysCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeysCommand {
+    /// Generate new signing keys
+    Generate {
+        #[arg(long, value_enum, default_value_t)]
+        cipher: Cipher,
+        /// Overwrite the existing keys
+        #[arg(long)]
+        force: bool,
+    },
+    /// Show the fingerprints and validity of the keys
+    Fingerprint,
+    /// Extend the expiration of the keys
+    Extend {
+        /// Number of days from now until the keys expire
+        #[arg(long, default_value_t = 1095)]
+        days: u64,
+    },
+    /// Add a new signing subkey, which is used for signing from now on
+    AddSubkey {
+        #[arg(long, value_enum, default_value_t)]
+        cipher: Cipher,
+    },
+    /// Revoke a signing subkey
+    RevokeSubkey {
+        /// Fingerprint or key ID of the subkey
+        subkey: KeyHandle,
+    },
+    /// Export the public key
+    Export {
+        /// Export the key ASCII armored
+        #[arg(long)]
+        armor: bool,
+        /// File to write the key to, instead of the standard output
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Sign the standard input, as the `command:` external signer
+    Sign {
+        #[arg(value_enum)]
+        operation: Operation,
+    },
+    /// Serve the signatures on a Unix socket, as the `unix:` external signer
+    Serve {
+        /// Path of the socket
+        socket: PathBuf,
+    },
+}
+
+/// Cipher suites of the keys.
+#[derive(Clone, Copy, Default, ValueEnum)]
+enum Cipher {
+    #[default]
+    Cv25519,
+    /// Supported by the old `gpgv` versions, e.g. of Debian 9 and CentOS 7
+    Rsa4096,
+}
+
+impl From<Cipher> for CipherSuite {
+    fn from(cipher: Cipher) -> Self {
+        match cipher {
+            Cipher::Cv25519 => CipherSuite::Cv25519,
+            Cipher::Rsa4096 => CipherSuite::RSA4k,
+        }
+    }
+}
+
+/// Signer with the keys at `path` along with the previous keys.
+fn key_signer(path: &Path, passphrase: Password) -> Result<KeySigner> {
+    let keyring = Keyring::from_env(pgp::load_cert(path)?)?;
+    Ok(KeySigner::new(Arc::new(keyring), passphrase))
+}
+
+/// Passphrase of the keys, which is only read by the commands using the secret keys.
+fn passphrase() -> Result<Password> {
+    let passphrase = var("PACKHUB_SIGN_PASSPHRASE")
+        .context("PACKHUB_SIGN_PASSPHRASE is required to use the secret keys")?;
+    Ok(passphrase.into())
+}
+
+fn manage_keys(path: PathBuf, command: KeysCommand) -> Result<()> {
+    match command {
+        KeysCommand::Generate { cipher, force } => {
+            if path.exists() && !force {
+                anyhow::bail!(
+                    "{} already exists, use --force to overwrite it",
+                    path.display()
+                );
+            }
+            let cert = pgp::generate_cert(&passphrase()?, cipher.into())?;
+            pgp::save_cert(&cert, &path)?;
+            print!("{}", pgp::describe_cert(&cert));
+        }
+        KeysCommand::Fingerprint => {
+            let cert = pgp::load_cert(&path)?;
+            print!("{}", pgp::describe_cert(&cert));
+        }
+        KeysCommand::Extend { days } => {
+            let expiration = SystemTime::now() + Duration::from_secs(days * 24 * 60 * 60);
+            let cert = pgp::set_expiration(pgp::load_cert(&path)?, &passphrase()?, expiration)?;
+            pgp::save_cert(&cert, &path)?;
+            print!("{}", pgp::describe_cert(&cert));
+        }
+        KeysCommand::AddSubkey { cipher } => {
+            let cert =
+                pgp::add_signing_subkey(pgp::load_cert(&path)?, &passphrase()?, cipher.into())?;
+            pgp::save_cert(&cert, &path)?;
+            print!("{}", pgp::describe_cert(&cert));
+        }
+        KeysCommand::RevokeSubkey { subkey } => {
+            let cert = pgp::revoke_subkey(pgp::load_cert(&path)?, &passphrase()?, &subkey)?;
+            pgp::save_cert(&cert, &path)?;
+            print!("{}", pgp::describe_cert(&cert));
+        }
+        KeysCommand::Export { armor, output } => {
+            let cert = pgp::load_cert(&path)?;
+            let key = pgp::public_keyring(&[&cert], armor)?;
+            match output {
+                Some(output) => fs::write(output, key)?,
+                None => stdout().write_all(&key)?,
+            }
+        }
+        KeysCommand::Sign { operation } => {
+            let mut data = Vec::new();
+            stdin().read_to_end(&mut data)?;
+            let signature = operation.sign(&key_signer(&path, passphrase()?)?, &data)?;
+            stdout().write_all(&signature)?;
+        }
+        KeysCommand::Serve { socket } => {
+            signer::serve(Arc::new(key_signer(&path, passphrase()?)?), &socket)?;
+        }
+    }
+
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() {
@@ -27,16 +202,18 @@ async fn main() {
         info!("No .env file found");
     }
 
-    let mut generate_keys = false;
+    let cli = Cli::parse();
 
-    if args().len() > 1 {
-        let arg = args().nth(1).unwrap();
-        if arg == "--generate-keys" {
-            generate_keys = true;
+    if let Some(Command::Keys { key, command }) = cli.command {
+        let path = key.unwrap_or_else(pgp::key_path);
+        if let Err(e) = manage_keys(path, command) {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
         }
+        return;
     }
 
-    let state = AppState::initialize(generate_keys).await;
+    let state = AppState::initialize(cli.generate_keys).await;
 
     let http_addr: SocketAddr = format!("0.0.0.0:{}", var("PACKHUB_HTTP_PORT").unwrap())
         .parse()
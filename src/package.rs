@@ -2,6 +2,7 @@ use std::sync::{Arc, Mutex};
 
 use anyhow::{Result, bail};
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     REQWEST,
@@ -16,6 +17,7 @@ struct InnerPackage {
     ver: String,
     data: Mutex<Data>,
     created: DateTime<Utc>,
+    verification: Mutex<Verification>,
 }
 
 #[derive(Clone, PartialEq)]
@@ -40,6 +42,30 @@ pub enum Data {
     None,
 }
 
+/// Status of the verification of the package with the signature of its upstream maintainer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verification {
+    /// The release doesn't provide a signature for the package or the key of the maintainer
+    /// isn't declared.
+    #[default]
+    Unverified,
+    /// The package has a valid signature made by the maintainer.
+    Verified,
+    /// The signature of the package wasn't made by the maintainer or doesn't match the package.
+    Invalid,
+}
+
+impl Verification {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Verification::Unverified => "unverified",
+            Verification::Verified => "verified",
+            Verification::Invalid => "invalid",
+        }
+    }
+}
+
 impl std::fmt::Debug for Package {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.file_name())
@@ -93,6 +119,7 @@ impl Package {
             ver,
             data: Mutex::new(Data::None),
             created,
+            verification: Mutex::new(Verification::default()),
         };
 
         Ok(Self {
@@ -108,12 +135,12 @@ impl Package {
     /// Architecture of the package.
     /// By default, it is `amd64`.
     pub fn architecture(&self) -> Arch {
-        self.inner
-            .info
-            .architecture
-            .as_ref()
-            .cloned()
-            .unwrap_or_default()
+        self.inner.info.architecture.unwrap_or_default()
+    }
+
+    /// Architecture of the package if it could be detected from the file name.
+    pub fn detected_architecture(&self) -> Option<Arch> {
+        self.inner.info.architecture
     }
 
     pub fn ty(&self) -> &Type {
@@ -135,7 +162,7 @@ impl Package {
     }
 
     pub fn file_name(&self) -> &str {
-        self.inner.url.split('/').last().unwrap()
+        self.inner.url.split('/').next_back().unwrap()
     }
 
     /// Download package data
@@ -177,6 +204,15 @@ impl Package {
         *self.inner.data.lock().unwrap() = Data::Metadata(metadata);
     }
 
+    /// Status of the verification of the package with the signature of its maintainer.
+    pub fn verification(&self) -> Verification {
+        *self.inner.verification.lock().unwrap()
+    }
+
+    pub fn set_verification(&self, verification: Verification) {
+        *self.inner.verification.lock().unwrap() = verification;
+    }
+
     /// Check if metadata is available.
     pub fn is_metadata_available(&self) -> bool {
         matches!(*self.inner.data.lock().unwrap(), Data::Metadata(_))
@@ -1,23 +1,101 @@
-use std::{fs, io::Write};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 
-use anyhow::Result;
-use axum::{Router, extract::State, routing::get};
+use anyhow::{Context, Result, anyhow};
+use askama::Template;
+use axum::{
+    Router,
+    extract::{Path as UrlPath, Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use chrono::{DateTime, Utc};
 use sequoia_openpgp::{
-    cert::prelude::*,
-    crypto::Password,
-    parse::Parse,
+    KeyHandle, armor,
+    cert::{SubkeyRevocationBuilder, prelude::*},
+    crypto::{KeyPair, Password},
+    parse::{
+        Parse,
+        stream::{DetachedVerifierBuilder, MessageLayer, MessageStructure, VerificationHelper},
+    },
     policy::StandardPolicy,
     serialize::{
-        SerializeInto,
+        Serialize, SerializeInto,
         stream::{Armorer, Message, Signer},
     },
+    types::{KeyFlags, ReasonForRevocation, RevocationStatus},
+};
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use tracing::warn;
+
+use crate::{
+    error::AppError,
+    ident::{Owner, Repo},
+    state::AppState,
 };
 
-use crate::state::AppState;
+const DEFAULT_KEY_PATH: &str = "key.gpg";
+const DEFAULT_EMAIL_DOMAIN: &str = "packhub.dev";
+
+/// Domain of the email address in the user IDs of the generated certs.
+///
+/// It is the host of the `PACKHUB_DOMAIN` variable, so that the keys can be discovered
+/// with the Web Key Directory of the instance.
+pub(crate) fn email_domain() -> String {
+    let host = dotenvy::var("PACKHUB_DOMAIN").unwrap_or_default();
+    let host = host
+        .split_once("://")
+        .map_or(host.as_str(), |(_, host)| host);
+    let host = host.split(['/', ':']).next().unwrap_or_default();
+
+    if host.is_empty() {
+        DEFAULT_EMAIL_DOMAIN.to_owned()
+    } else {
+        host.to_lowercase()
+    }
+}
+
+/// User ID of the generated certs with the `name`.
+fn user_id(name: &str) -> String {
+    format!("{name} <sign@{}>", email_domain())
+}
+
+/// Path of the file storing the signing keys.
+///
+/// It can be configured with the `PACKHUB_KEY_PATH` variable.
+pub fn key_path() -> PathBuf {
+    dotenvy::var("PACKHUB_KEY_PATH")
+        .unwrap_or_else(|_| DEFAULT_KEY_PATH.to_owned())
+        .into()
+}
 
 fn generate_keys(passphrase: &Password) -> Result<Cert> {
+    generate_cert(passphrase, CipherSuite::Cv25519)
+}
+
+/// Generate a cert with a signing subkey using the `cipher` suite.
+///
+/// RSA keys are required by the old `gpgv` versions, e.g. of Debian 9 and CentOS 7.
+pub fn generate_cert(passphrase: &Password, cipher: CipherSuite) -> Result<Cert> {
+    build_cert(&user_id("PackHub"), passphrase, cipher)
+}
+
+/// Generate a cert dedicated to the `owner/repo` repository.
+pub fn generate_repository_cert(passphrase: &Password, repository: &str) -> Result<Cert> {
+    let user_id = user_id(&format!("PackHub ({repository})"));
+    build_cert(&user_id, passphrase, CipherSuite::Cv25519)
+}
+
+fn build_cert(user_id: &str, passphrase: &Password, cipher: CipherSuite) -> Result<Cert> {
     let (cert, _) = CertBuilder::new()
-        .add_userid("PackHub <sign@packhub.dev>")
+        .add_userid(user_id)
+        .set_cipher_suite(cipher)
         .set_password(Some(passphrase.clone()))
         .add_signing_subkey()
         .generate()?;
@@ -25,26 +103,250 @@ fn generate_keys(passphrase: &Password) -> Result<Cert> {
     Ok(cert)
 }
 
+/// Whether each repository is signed with its own key instead of the key of the instance.
+///
+/// It can be enabled with the `PACKHUB_REPOSITORY_KEYS` variable. The keys are generated
+/// when the install script or the indices of a repository with a release are requested.
+pub fn repository_keys() -> bool {
+    dotenvy::var("PACKHUB_REPOSITORY_KEYS").is_ok_and(|v| v == "true")
+}
+
 pub fn generate_and_save_keys(passphrase: &Password) -> Result<Cert> {
     let cert = generate_keys(passphrase)?;
 
+    save_cert(&cert, key_path())?;
+
+    Ok(cert)
+}
+
+/// Save the certificate with its secret keys to the file at `path`.
+pub fn save_cert(cert: &Cert, path: impl AsRef<Path>) -> Result<()> {
     let key = cert.as_tsk().to_vec()?;
 
-    fs::write("key.gpg", key)?;
+    fs::write(path, key)?;
 
-    Ok(cert)
+    Ok(())
 }
 
 pub fn load_cert_from_file() -> Result<Cert> {
-    let key = fs::read("key.gpg")?;
+    load_cert(key_path())
+}
+
+/// Load the certificate with its secret keys from the file at `path`.
+pub fn load_cert(path: impl AsRef<Path>) -> Result<Cert> {
+    let key = fs::read(path)?;
     let cert = Cert::from_bytes(&key)?;
 
     Ok(cert)
 }
 
-pub fn clearsign_metadata(data: &str, cert: &Cert, passphrase: &Password) -> Result<Vec<u8>> {
+/// Decrypt the primary key of the `cert`, which certifies its subkeys.
+fn primary_keypair(cert: &Cert, passphrase: &Password) -> Result<KeyPair> {
+    let key = cert.primary_key().key().clone().parts_into_secret()?;
+
+    key.decrypt_secret(passphrase)?.into_keypair()
+}
+
+/// Set the expiration time of the cert and its signing subkeys.
+pub fn set_expiration(cert: Cert, passphrase: &Password, expiration: SystemTime) -> Result<Cert> {
+    let policy = StandardPolicy::new();
+    let mut primary = primary_keypair(&cert, passphrase)?;
+
+    let signatures = {
+        let vc = cert.with_policy(&policy, None)?;
+        let mut signatures = vc
+            .primary_key()
+            .set_expiration_time(&mut primary, Some(expiration))?;
+
+        // Signing subkeys need to sign their new bindings too
+        for key in vc.keys().subkeys().secret().revoked(false).for_signing() {
+            let mut subkey = key
+                .key()
+                .clone()
+                .decrypt_secret(passphrase)?
+                .into_keypair()?;
+            signatures.extend(key.set_expiration_time(
+                &mut primary,
+                Some(&mut subkey),
+                Some(expiration),
+            )?);
+        }
+
+        signatures
+    };
+
+    Ok(cert.insert_packets(signatures)?.0)
+}
+
+/// Add a new signing subkey using the `cipher` suite to the cert.
+///
+/// The new subkey is preferred over the existing ones for signing.
+pub fn add_signing_subkey(cert: Cert, passphrase: &Password, cipher: CipherSuite) -> Result<Cert> {
+    let policy = StandardPolicy::new();
+    let primary = primary_keypair(&cert, passphrase)?;
+
+    let cert = KeyBuilder::new(KeyFlags::empty().set_signing())
+        .set_cipher_suite(cipher)
+        .set_password(Some(passphrase.clone()))
+        .subkey(cert.with_policy(&policy, None)?)?
+        .set_primary_key_signer(primary)
+        .attach_cert()?;
+
+    Ok(cert)
+}
+
+/// Revoke the subkey of the cert identified by the `handle`, as it is retired.
+pub fn revoke_subkey(cert: Cert, passphrase: &Password, handle: &KeyHandle) -> Result<Cert> {
+    let mut primary = primary_keypair(&cert, passphrase)?;
+
+    let signature = {
+        let subkey = cert
+            .keys()
+            .subkeys()
+            .key_handle(handle.clone())
+            .next()
+            .context(format!("No subkey found with the handle: {handle}"))?;
+
+        SubkeyRevocationBuilder::new()
+            .set_reason_for_revocation(ReasonForRevocation::KeyRetired, b"Retired")?
+            .build(&mut primary, &cert, subkey.key(), None)?
+    };
+
+    Ok(cert.insert_packets(signature)?.0)
+}
+
+/// Describe the cert and its subkeys, with their fingerprints and validity.
+pub fn describe_cert(cert: &Cert) -> String {
+    let policy = StandardPolicy::new();
+    let time = |time: Option<SystemTime>| {
+        time.map_or("never".to_owned(), |time| {
+            DateTime::<Utc>::from(time).to_rfc3339()
+        })
+    };
+
+    let mut description = format!("Cert: {}\n", cert.fingerprint());
+    for uid in cert.userids() {
+        description.push_str(&format!("User ID: {}\n", uid.userid()));
+    }
+
+    for key in cert.keys() {
+        let role = if key.primary() {
+            "Primary key"
+        } else {
+            "Subkey"
+        };
+        let status = match key.with_policy(&policy, None) {
+            Ok(key) if key.revocation_status() != RevocationStatus::NotAsFarAsWeKnow => {
+                "revoked".to_owned()
+            }
+            Ok(key) => format!(
+                "{}, expires: {}{}",
+                if key.alive().is_ok() {
+                    "alive"
+                } else {
+                    "expired"
+                },
+                time(key.key_expiration_time()),
+                if key.for_signing() { ", signing" } else { "" },
+            ),
+            Err(e) => format!("invalid: {e}"),
+        };
+        description.push_str(&format!(
+            "{role}: {} ({}, created: {}, {status})\n",
+            key.key().fingerprint(),
+            key.key().pk_algo(),
+            time(Some(key.key().creation_time())),
+        ));
+    }
+
+    description
+}
+
+/// Signing keys of PackHub.
+///
+/// The previous keys keep signing the metadata along with the current key until the end of
+/// the transition, so that the clients can switch to the current key before the previous
+/// ones are retired.
+pub struct Keyring {
+    current: Cert,
+    previous: Vec<Cert>,
+    transition_end: Option<DateTime<Utc>>,
+}
+
+impl Keyring {
+    pub fn new(current: Cert, previous: Vec<Cert>, transition_end: Option<DateTime<Utc>>) -> Self {
+        Self {
+            current,
+            previous,
+            transition_end,
+        }
+    }
+
+    /// Keyring of the `current` key with the previous keys configured with the
+    /// `PACKHUB_PREVIOUS_KEYS` and `PACKHUB_KEY_TRANSITION_END` variables.
+    pub fn from_env(current: Cert) -> Result<Self> {
+        let previous = dotenvy::var("PACKHUB_PREVIOUS_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|path| !path.is_empty())
+            .map(load_cert)
+            .collect::<Result<_>>()?;
+        let transition_end = dotenvy::var("PACKHUB_KEY_TRANSITION_END")
+            .ok()
+            .filter(|end| !end.is_empty())
+            .map(|end| DateTime::parse_from_rfc3339(&end).map(|end| end.to_utc()))
+            .transpose()?;
+
+        Ok(Self::new(current, previous, transition_end))
+    }
+
+    /// The key used for the signatures which can only be made by a single key.
+    pub fn current(&self) -> &Cert {
+        &self.current
+    }
+
+    /// Keys signing the metadata, the current one first.
+    pub fn active(&self) -> Vec<&Cert> {
+        self.active_at(Utc::now())
+    }
+
+    fn active_at(&self, now: DateTime<Utc>) -> Vec<&Cert> {
+        let mut certs = vec![&self.current];
+        if self.transition_end.is_none_or(|end| now < end) {
+            // The expired or revoked previous keys can't make signatures anymore
+            certs.extend(self.previous.iter().filter(|cert| can_sign_at(cert, now)));
+        }
+
+        certs
+    }
+}
+
+/// Whether the `cert` has a signing key which is neither expired nor revoked at `time`.
+fn can_sign_at(cert: &Cert, time: DateTime<Utc>) -> bool {
+    let policy = StandardPolicy::new();
+    let Ok(cert) = cert.with_policy(&policy, SystemTime::from(time)) else {
+        return false;
+    };
+
+    cert.alive().is_ok()
+        && !matches!(cert.revocation_status(), RevocationStatus::Revoked(_))
+        && cert
+            .keys()
+            .supported()
+            .alive()
+            .revoked(false)
+            .for_signing()
+            .next()
+            .is_some()
+}
+
+/// Decrypt the most recent signing key of the `cert`.
+///
+/// `None` is returned if the cert doesn't have any usable signing key, e.g. it has expired.
+fn signing_keypair(cert: &Cert, passphrase: &Password) -> Result<Option<KeyPair>> {
     let binding = StandardPolicy::new();
-    let key = cert
+    let Some(key) = cert
         .keys()
         .secret()
         .with_policy(&binding, None)
@@ -52,17 +354,39 @@ pub fn clearsign_metadata(data: &str, cert: &Cert, passphrase: &Password) -> Res
         .alive()
         .revoked(false)
         .for_signing()
-        .next()
-        .unwrap()
-        .key()
-        .clone();
+        .max_by_key(|key| key.key().creation_time())
+    else {
+        return Ok(None);
+    };
+
+    let decrypted_key = key.key().clone().decrypt_secret(passphrase)?;
+    Ok(Some(decrypted_key.into_keypair()?))
+}
 
-    let decrypted_key = key.decrypt_secret(passphrase)?;
-    let keypair = decrypted_key.into_keypair()?;
+/// Create a signer which signs with the keys of all the `certs`.
+fn signer<'a>(message: Message<'a>, certs: &[&Cert], passphrase: &Password) -> Result<Signer<'a>> {
+    let mut keypairs = Vec::new();
+    for cert in certs {
+        match signing_keypair(cert, passphrase)? {
+            Some(keypair) => keypairs.push(keypair),
+            None => warn!("No usable signing key in the cert {}", cert.fingerprint()),
+        }
+    }
+
+    let mut keypairs = keypairs.into_iter();
+    let keypair = keypairs.next().context("No usable signing key found")?;
+    let mut signer = Signer::new(message, keypair)?;
+    for keypair in keypairs {
+        signer = signer.add_signer(keypair)?;
+    }
 
+    Ok(signer)
+}
+
+pub fn clearsign_metadata(data: &str, certs: &[&Cert], passphrase: &Password) -> Result<Vec<u8>> {
     let mut sink = vec![];
     let message = Message::new(&mut sink);
-    let mut signer = Signer::new(message, keypair)?.cleartext().build()?;
+    let mut signer = signer(message, certs, passphrase)?.cleartext().build()?;
 
     signer.write_all(data.as_bytes())?;
     signer.finalize()?;
@@ -70,48 +394,368 @@ pub fn clearsign_metadata(data: &str, cert: &Cert, passphrase: &Password) -> Res
     Ok(sink)
 }
 
-pub fn detached_sign_metadata(content: &str, cert: &Cert, passphrase: &Password) -> Result<Vec<u8>> {
+pub fn detached_sign_metadata(
+    content: &str,
+    certs: &[&Cert],
+    passphrase: &Password,
+) -> Result<Vec<u8>> {
+    let mut sink = vec![];
+    let message = Armorer::new(Message::new(&mut sink)).build()?;
+    let mut signer = signer(message, certs, passphrase)?.detached().build()?;
+
+    signer.write_all(content.as_bytes())?;
+    signer.finalize()?;
+
+    Ok(sink)
+}
+
+/// Create a binary detached signature of the `data`, as used in the RPM package headers.
+pub fn detached_sign_binary(data: &[u8], cert: &Cert, passphrase: &Password) -> Result<Vec<u8>> {
+    let mut sink = vec![];
+    let message = Message::new(&mut sink);
+    let mut signer = signer(message, &[cert], passphrase)?.detached().build()?;
+
+    signer.write_all(data)?;
+    signer.finalize()?;
+
+    Ok(sink)
+}
+
+struct VerificationHelperImpl {
+    public_key: Cert,
+}
+
+impl VerificationHelper for VerificationHelperImpl {
+    fn get_certs(&mut self, _ids: &[KeyHandle]) -> sequoia_openpgp::Result<Vec<Cert>> {
+        Ok(vec![self.public_key.clone()])
+    }
+
+    fn check(&mut self, structure: MessageStructure<'_>) -> sequoia_openpgp::Result<()> {
+        for layer in structure.into_iter() {
+            // Simply check if all signatures are valid
+            if let MessageLayer::SignatureGroup { ref results } = layer
+                && !results.iter().any(|r| r.is_ok())
+            {
+                return Err(anyhow::anyhow!("No valid signature"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Verify the detached `signature` of the `data` made by the `cert`.
+///
+/// The signature can be either ASCII armored or binary.
+pub fn verify_detached_signature(data: &[u8], signature: &[u8], cert: &Cert) -> Result<()> {
+    let policy = StandardPolicy::new();
+    let helper = VerificationHelperImpl {
+        public_key: cert.clone(),
+    };
+
+    let mut verifier =
+        DetachedVerifierBuilder::from_bytes(signature)?.with_policy(&policy, None, helper)?;
+    verifier.verify_bytes(data)?;
+
+    Ok(())
+}
+
+/// Export the public keys of the `certs` as a single keyring.
+pub fn public_keyring(certs: &[&Cert], armored: bool) -> Result<Vec<u8>> {
+    let mut keyring = Vec::new();
+    for cert in certs {
+        cert.serialize(&mut keyring)?;
+    }
+
+    if !armored {
+        return Ok(keyring);
+    }
+
+    let mut sink = Vec::new();
+    let mut writer = armor::Writer::new(&mut sink, armor::Kind::PublicKey)?;
+    writer.write_all(&keyring)?;
+    writer.finalize()?;
+
+    Ok(sink)
+}
+
+/// Key ID of the signing key, which identifies the `debsig` policy of the key.
+///
+/// It fails when the certificate has no valid signing key, e.g. after it expired or was revoked.
+pub fn signing_key_id(cert: &Cert) -> Result<String> {
     let binding = StandardPolicy::new();
     let key = cert
         .keys()
-        .secret()
         .with_policy(&binding, None)
         .supported()
         .alive()
         .revoked(false)
         .for_signing()
-        .next()
-        .unwrap()
-        .key()
-        .clone();
+        .max_by_key(|key| key.key().creation_time())
+        .with_context(|| format!("No valid signing key in {}", cert.fingerprint()))?;
 
-    let decrypted_key = key.decrypt_secret(passphrase)?;
-    let keypair = decrypted_key.into_keypair()?;
+    Ok(key.key().keyid().to_hex())
+}
 
-    let mut sink = vec![];
-    let message = Armorer::new(Message::new(&mut sink)).build()?;
-    let mut signer = Signer::new(message, keypair)?.detached().build()?;
+/// Hash of the local part of an email address, which locates its key in a Web Key Directory.
+///
+/// It is the z-base-32 encoded SHA-1 digest of the lowercase local part.
+fn wkd_hash(local_part: &str) -> String {
+    const ALPHABET: &[u8] = b"ybndrfg8ejkmcpqxot1uwisza345h769";
 
-    signer.write_all(content.as_bytes())?;
-    signer.finalize()?;
+    let digest = Sha1::digest(local_part.to_lowercase().as_bytes());
+    // Every 5 bytes are encoded into 8 characters
+    digest
+        .chunks(5)
+        .flat_map(|chunk| {
+            let bits = chunk
+                .iter()
+                .fold(0u64, |bits, &byte| bits << 8 | byte as u64);
+            (0..8)
+                .rev()
+                .map(move |i| ALPHABET[(bits >> (i * 5)) as usize & 31] as char)
+        })
+        .collect()
+}
 
-    Ok(sink)
+/// Certs having a user ID with an email address of the `domain` whose local part has
+/// the WKD `hash`.
+fn wkd_lookup<'a>(certs: &[&'a Cert], domain: &str, hash: &str) -> Vec<&'a Cert> {
+    certs
+        .iter()
+        .filter(|cert| {
+            cert.userids().any(|uid| {
+                let email = uid.userid().email().ok().flatten();
+                email
+                    .and_then(|email| email.rsplit_once('@'))
+                    .is_some_and(|(local, host)| {
+                        host.eq_ignore_ascii_case(domain) && wkd_hash(local) == hash
+                    })
+            })
+        })
+        .copied()
+        .collect()
+}
+
+/// Certs matching an HKP search, either by the fingerprint or key ID of any of their keys
+/// when prefixed with `0x`, or by a part of their user IDs.
+fn hkp_lookup<'a>(certs: &[&'a Cert], search: &str) -> Vec<&'a Cert> {
+    let search = search.trim();
+
+    certs
+        .iter()
+        .filter(|cert| match search.strip_prefix("0x") {
+            Some(id) => cert.keys().any(|key| {
+                let id = id.to_uppercase();
+                key.key().fingerprint().to_hex() == id || key.key().keyid().to_hex() == id
+            }),
+            None => cert.userids().any(|uid| {
+                uid.userid()
+                    .to_string()
+                    .to_lowercase()
+                    .contains(&search.to_lowercase())
+            }),
+        })
+        .copied()
+        .collect()
+}
+
+/// Machine readable HKP index of the certs.
+fn hkp_index(certs: &[&Cert]) -> String {
+    let policy = StandardPolicy::new();
+    let time = |time: Option<SystemTime>| {
+        time.and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|time| time.as_secs().to_string())
+            .unwrap_or_default()
+    };
+    // `:` separates the fields, so it must be escaped in the user IDs
+    let escape = |uid: String| uid.replace('%', "%25").replace(':', "%3A");
+
+    let mut index = format!("info:1:{}\n", certs.len());
+    for cert in certs {
+        let key = cert.primary_key().key();
+        let (expiration, flags) = match cert.with_policy(&policy, None) {
+            Ok(vc) if vc.revocation_status() != RevocationStatus::NotAsFarAsWeKnow => {
+                (vc.primary_key().key_expiration_time(), "r")
+            }
+            Ok(vc) if vc.alive().is_err() => (vc.primary_key().key_expiration_time(), "e"),
+            Ok(vc) => (vc.primary_key().key_expiration_time(), ""),
+            Err(_) => (None, "e"),
+        };
+
+        index.push_str(&format!(
+            "pub:{}:{}:{}:{}:{}:{flags}\n",
+            key.fingerprint().to_hex(),
+            u8::from(key.pk_algo()),
+            key.mpis()
+                .bits()
+                .map(|bits| bits.to_string())
+                .unwrap_or_default(),
+            time(Some(key.creation_time())),
+            time(expiration),
+        ));
+        for uid in cert.userids() {
+            index.push_str(&format!(
+                "uid:{}:{}::{flags}\n",
+                escape(uid.userid().to_string()),
+                time(
+                    uid.self_signatures()
+                        .next()
+                        .and_then(|sig| sig.signature_creation_time())
+                ),
+            ));
+        }
+    }
+
+    index
 }
 
 /////////////////////////////////////// Axum handlers /////////////////////////////////////////////////
 
 async fn armored_public_key_handler(State(state): State<AppState>) -> Vec<u8> {
-    state.armored_public_key()
+    state.keys().armored_public_key()
 }
 
 async fn dearmored_public_key_handler(State(state): State<AppState>) -> Vec<u8> {
-    state.dearmored_public_key()
+    state.keys().dearmored_public_key()
+}
+
+/// Public key signing the repository, `<repo>.asc` ASCII armored or `<repo>.gpg` in binary.
+async fn repository_key_handler(
+    State(state): State<AppState>,
+    UrlPath((owner, file)): UrlPath<(Owner, String)>,
+) -> Result<Vec<u8>, AppError> {
+    let (repo, armored) = match file.rsplit_once('.') {
+        Some((repo, "asc")) => (repo, true),
+        Some((repo, "gpg")) => (repo, false),
+        _ => return Err(anyhow!("Unknown key requested: {file}").into()),
+    };
+    let repo = Repo::try_from(repo.to_owned())?;
+
+    let keys = state.repository_keys(&owner, &repo).await?;
+    if armored {
+        Ok(keys.armored_public_key())
+    } else {
+        Ok(keys.dearmored_public_key())
+    }
+}
+
+/// Keys of the instance in the Web Key Directory, for `gpg --locate-keys`.
+async fn wkd_handler(
+    State(state): State<AppState>,
+    UrlPath(hash): UrlPath<String>,
+) -> Result<Response, AppError> {
+    wkd_keys(&state, &email_domain(), &hash)
+}
+
+/// The advanced method of WKD, which has the domain in the path.
+async fn wkd_advanced_handler(
+    State(state): State<AppState>,
+    UrlPath((domain, hash)): UrlPath<(String, String)>,
+) -> Result<Response, AppError> {
+    wkd_keys(&state, &domain, &hash)
+}
+
+fn wkd_keys(state: &AppState, domain: &str, hash: &str) -> Result<Response, AppError> {
+    let keys = state.keys();
+    let certs = wkd_lookup(&keys.certs(), domain, hash);
+    if certs.is_empty() {
+        return Err(anyhow!("No key found with the hash: {hash}@{domain}").into());
+    }
+
+    let keyring = public_keyring(&certs, false)?;
+    Ok((
+        [(header::CONTENT_TYPE, "application/octet-stream")],
+        keyring,
+    )
+        .into_response())
+}
+
+/// The policy flags of the Web Key Directory, which must exist even when they are empty.
+async fn wkd_policy_handler() -> &'static str {
+    ""
+}
+
+#[derive(Deserialize)]
+struct HkpQuery {
+    op: String,
+    search: String,
+}
+
+/// Minimal HKP lookup of the keys of the instance, for `gpg --recv-keys` and `gpg --search-keys`.
+async fn hkp_lookup_handler(
+    State(state): State<AppState>,
+    Query(query): Query<HkpQuery>,
+) -> Result<Response, AppError> {
+    let keys = state.keys();
+    let certs = hkp_lookup(&keys.certs(), &query.search);
+    if certs.is_empty() {
+        return Err(anyhow!("No key found for: {}", query.search).into());
+    }
+
+    match query.op.as_str() {
+        "get" => {
+            let keyring = public_keyring(&certs, true)?;
+            Ok(([(header::CONTENT_TYPE, "application/pgp-keys")], keyring).into_response())
+        }
+        "index" | "vindex" => Ok(hkp_index(&certs).into_response()),
+        op => Err(anyhow!("Unsupported HKP operation: {op}").into()),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "debsig.pol", escape = "none")]
+struct DebsigPolicy {
+    key_id: String,
+}
+
+/// Policy of `debsig-verify` for the packages signed by PackHub.
+///
+/// It should be installed as `/etc/debsig/policies/<key_id>/packhub.pol` along with
+/// the key as `/usr/share/debsig/keyrings/<key_id>/packhub.gpg`.
+async fn debsig_policy_handler(State(state): State<AppState>) -> Result<String, AppError> {
+    debsig_policy(state.keys().signing_key_id()?)
+}
+
+/// Policy of `debsig-verify` for the packages of the repository.
+async fn repository_debsig_policy_handler(
+    State(state): State<AppState>,
+    UrlPath((owner, repo)): UrlPath<(Owner, Repo)>,
+) -> Result<String, AppError> {
+    let keys = state.repository_keys(&owner, &repo).await?;
+    debsig_policy(keys.signing_key_id()?)
+}
+
+fn debsig_policy(key_id: String) -> Result<String, AppError> {
+    let policy = DebsigPolicy { key_id };
+    Ok(policy.render()?)
 }
 
 pub fn keys() -> Router<AppState> {
     Router::new()
         .route("/packhub.asc", get(armored_public_key_handler))
         .route("/packhub.gpg", get(dearmored_public_key_handler))
+        .route("/packhub.pol", get(debsig_policy_handler))
+        .route("/github/{owner}/{file}", get(repository_key_handler))
+        .route(
+            "/github/{owner}/{repo}/packhub.pol",
+            get(repository_debsig_policy_handler),
+        )
+}
+
+/// Discovery of the keys of the instance with the Web Key Directory and a keyserver (HKP).
+pub fn key_discovery() -> Router<AppState> {
+    Router::new()
+        .route("/.well-known/openpgpkey/hu/{hash}", get(wkd_handler))
+        .route("/.well-known/openpgpkey/policy", get(wkd_policy_handler))
+        .route(
+            "/.well-known/openpgpkey/{domain}/hu/{hash}",
+            get(wkd_advanced_handler),
+        )
+        .route(
+            "/.well-known/openpgpkey/{domain}/policy",
+            get(wkd_policy_handler),
+        )
+        .route("/pks/lookup", get(hkp_lookup_handler))
 }
 
 #[cfg(test)]
@@ -120,42 +764,11 @@ mod tests {
 
     use anyhow::Result;
     use sequoia_openpgp::{
-        cert::prelude::*,
-        parse::Parse,
-        parse::stream::{MessageLayer, MessageStructure, VerificationHelper, VerifierBuilder},
-        policy::StandardPolicy,
+        Packet, parse::Parse, parse::stream::VerifierBuilder, policy::StandardPolicy,
     };
 
     use super::*;
 
-    struct VerificationHelperImpl {
-        public_key: Cert,
-    }
-
-    impl VerificationHelper for VerificationHelperImpl {
-        fn get_certs(
-            &mut self,
-            _ids: &[sequoia_openpgp::KeyHandle],
-        ) -> sequoia_openpgp::Result<Vec<Cert>> {
-            Ok(vec![self.public_key.clone()])
-        }
-
-        fn check(&mut self, structure: MessageStructure<'_>) -> sequoia_openpgp::Result<()> {
-            for layer in structure.into_iter() {
-                match layer {
-                    MessageLayer::SignatureGroup { ref results } => {
-                        // Simply check if all signatures are valid
-                        if !results.iter().any(|r| r.is_ok()) {
-                            return Err(anyhow::anyhow!("No valid signature"));
-                        }
-                    }
-                    _ => {}
-                }
-            }
-            Ok(())
-        }
-    }
-
     #[test]
     fn test_pgp_sign_and_verify() -> Result<()> {
         let passphrase = "secure-passphrase".into();
@@ -165,7 +778,7 @@ mod tests {
         let message = "Test message to be signed";
 
         // Sign the message using cleartext signing
-        let signed_message = clearsign_metadata(message, &cert, &passphrase)?;
+        let signed_message = clearsign_metadata(message, &[&cert], &passphrase)?;
 
         // Set up verification
         let helper = VerificationHelperImpl {
@@ -174,8 +787,8 @@ mod tests {
         let policy = StandardPolicy::new();
 
         // Create verifier with our helper
-        let mut verifier = VerifierBuilder::from_bytes(&signed_message)?
-            .with_policy(&policy, None, helper)?;
+        let mut verifier =
+            VerifierBuilder::from_bytes(&signed_message)?.with_policy(&policy, None, helper)?;
 
         // Read the verified content
         let mut verified_content = Vec::new();
@@ -187,4 +800,234 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_detached_signature_verification() -> Result<()> {
+        let passphrase = "secure-passphrase".into();
+        let cert = generate_keys(&passphrase)?;
+        let other = generate_keys(&passphrase)?;
+        let data = b"Package data";
+
+        let signature = detached_sign_binary(data, &cert, &passphrase)?;
+        verify_detached_signature(data, &signature, &cert)?;
+        assert!(verify_detached_signature(b"Tampered data", &signature, &cert).is_err());
+        assert!(verify_detached_signature(data, &signature, &other).is_err());
+
+        // ASCII armored signatures are also accepted
+        let signature = detached_sign_metadata("Package data", &[&cert], &passphrase)?;
+        verify_detached_signature(data, &signature, &cert)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_signing_with_multiple_keys() -> Result<()> {
+        let passphrase = "secure-passphrase".into();
+        let current = generate_keys(&passphrase)?;
+        let previous = generate_keys(&passphrase)?;
+        let data = "Release file";
+
+        // Clients trusting either of the keys can verify the signature
+        let signature = detached_sign_metadata(data, &[&current, &previous], &passphrase)?;
+        verify_detached_signature(data.as_bytes(), &signature, &current)?;
+        verify_detached_signature(data.as_bytes(), &signature, &previous)?;
+
+        let keyring = public_keyring(&[&current, &previous], false)?;
+        let certs: Vec<Cert> = CertParser::from_bytes(&keyring)?.collect::<Result<_, _>>()?;
+        assert_eq!(certs.len(), 2);
+        assert!(certs.iter().all(|cert| !cert.is_tsk()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_transition() -> Result<()> {
+        let passphrase = "secure-passphrase".into();
+        let current = generate_keys(&passphrase)?;
+        let previous = generate_keys(&passphrase)?;
+        let end = Utc::now() + chrono::TimeDelta::days(30);
+
+        let keyring = Keyring::new(current.clone(), vec![previous.clone()], Some(end));
+        let fingerprints =
+            |certs: Vec<&Cert>| -> Vec<_> { certs.iter().map(|cert| cert.fingerprint()).collect() };
+
+        let before = end - chrono::TimeDelta::days(1);
+        assert_eq!(
+            fingerprints(keyring.active_at(before)),
+            [current.fingerprint(), previous.fingerprint()]
+        );
+        assert_eq!(
+            fingerprints(keyring.active_at(end)),
+            [current.fingerprint()]
+        );
+
+        // Without an end, the previous keys are kept until they are removed
+        let keyring = Keyring::new(current.clone(), vec![previous.clone()], None);
+        assert_eq!(keyring.active_at(end).len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_signing_during_key_transition() -> Result<()> {
+        let passphrase = "secure-passphrase".into();
+        let current = generate_keys(&passphrase)?;
+        let previous = generate_keys(&passphrase)?;
+        let end = Utc::now() + chrono::TimeDelta::days(30);
+        let keyring = Keyring::new(current.clone(), vec![previous.clone()], Some(end));
+        let data = "Release file";
+
+        let signature = detached_sign_metadata(data, &keyring.active_at(Utc::now()), &passphrase)?;
+        verify_detached_signature(data.as_bytes(), &signature, &current)?;
+        verify_detached_signature(data.as_bytes(), &signature, &previous)?;
+
+        let signature = detached_sign_metadata(data, &keyring.active_at(end), &passphrase)?;
+        verify_detached_signature(data.as_bytes(), &signature, &current)?;
+        assert!(verify_detached_signature(data.as_bytes(), &signature, &previous).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_public_only_keys() -> Result<()> {
+        let passphrase = "secure-passphrase".into();
+        let cert = generate_keys(&passphrase)?;
+        let previous = generate_keys(&passphrase)?;
+
+        // The external signers hold the secret keys, so only the public keys are loaded
+        let public = cert.clone().strip_secret_key_material();
+        let keyring = Keyring::new(
+            public.clone(),
+            vec![previous.strip_secret_key_material()],
+            None,
+        );
+        assert_eq!(keyring.active().len(), 2);
+        assert_eq!(
+            signing_key_id(keyring.current()).unwrap(),
+            signing_key_id(&cert).unwrap()
+        );
+
+        let published = public_keyring(&keyring.active(), false)?;
+        let certs: Vec<Cert> = CertParser::from_bytes(&published)?.collect::<Result<_, _>>()?;
+        assert_eq!(certs[0], public);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expired_and_revoked_previous_keys() -> Result<()> {
+        let passphrase: Password = "secure-passphrase".into();
+        let current = generate_keys(&passphrase)?;
+
+        let (expired, _) = CertBuilder::new()
+            .set_creation_time(SystemTime::from(Utc::now() - chrono::TimeDelta::days(2)))
+            .set_validity_period(std::time::Duration::from_secs(24 * 60 * 60))
+            .add_signing_subkey()
+            .generate()?;
+
+        let retired = generate_keys(&passphrase)?;
+        let mut primary = retired
+            .primary_key()
+            .key()
+            .clone()
+            .parts_into_secret()?
+            .decrypt_secret(&passphrase)?
+            .into_keypair()?;
+        let revocation = retired.revoke(&mut primary, ReasonForRevocation::KeyRetired, b"")?;
+        let (revoked, _) = retired.insert_packets(revocation)?;
+
+        let valid = generate_keys(&passphrase)?;
+        let now = Utc::now();
+        let keyring = Keyring::new(current.clone(), vec![expired, revoked, valid.clone()], None);
+
+        let fingerprints: Vec<_> = keyring
+            .active_at(now)
+            .iter()
+            .map(|cert| cert.fingerprint())
+            .collect();
+        assert_eq!(fingerprints, [current.fingerprint(), valid.fingerprint()]);
+
+        // Signing still works without the unusable keys
+        let signature =
+            detached_sign_metadata("Release file", &keyring.active_at(now), &passphrase)?;
+        verify_detached_signature(b"Release file", &signature, &valid)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_management() -> Result<()> {
+        let passphrase = "secure-passphrase".into();
+        let cert = generate_keys(&passphrase)?;
+        let old = signing_key_id(&cert)?;
+
+        // The new subkey is used for signing
+        let cert = add_signing_subkey(cert, &passphrase, CipherSuite::Cv25519)?;
+        let new = signing_key_id(&cert)?;
+        assert_ne!(old, new);
+
+        let signature = detached_sign_binary(b"data", &cert, &passphrase)?;
+        let Packet::Signature(signature) = Packet::from_bytes(&signature)? else {
+            panic!("Not a signature");
+        };
+        assert_eq!(signature.issuers().next().unwrap().to_hex(), new);
+
+        // The revoked subkey isn't usable anymore
+        let handle: KeyHandle = old.parse()?;
+        let cert = revoke_subkey(cert, &passphrase, &handle)?;
+        let policy = StandardPolicy::new();
+        let subkey = cert.keys().subkeys().key_handle(handle).next().unwrap();
+        assert!(matches!(
+            subkey.with_policy(&policy, None)?.revocation_status(),
+            RevocationStatus::Revoked(_)
+        ));
+
+        let expiration = SystemTime::now() + std::time::Duration::from_secs(10 * 365 * 86400);
+        let cert = set_expiration(cert, &passphrase, expiration)?;
+        let vc = cert.with_policy(&policy, None)?;
+        let expires = vc.primary_key().key_expiration_time().unwrap();
+        let drift = expires.duration_since(expiration).unwrap_or_default();
+        assert!(drift.as_secs() < 2);
+        let handle: KeyHandle = new.parse()?;
+        let subkey = vc.keys().subkeys().key_handle(handle).next().unwrap();
+        assert_eq!(subkey.key_expiration_time(), Some(expires));
+
+        assert!(describe_cert(&cert).contains("revoked"));
+
+        // The policy of a cert without a signing key can't be created
+        let cert = revoke_subkey(cert, &passphrase, &new.parse()?)?;
+        assert!(signing_key_id(&cert).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_discovery() -> Result<()> {
+        // Test vector of the WKD specification
+        assert_eq!(wkd_hash("Joe.Doe"), "iy9q119eutrkn8s1mk4r39qejnbu3n5q");
+
+        let passphrase = "passphrase".into();
+        let cert = generate_keys(&passphrase)?;
+        let certs = [&cert];
+
+        let domain = email_domain();
+        assert_eq!(wkd_lookup(&certs, &domain, &wkd_hash("sign")), [&cert]);
+        assert!(wkd_lookup(&certs, &domain, &wkd_hash("joe.doe")).is_empty());
+        assert!(wkd_lookup(&certs, "example.org", &wkd_hash("sign")).is_empty());
+
+        let fingerprint = format!("0x{}", cert.fingerprint().to_hex());
+        let key_id = format!("0x{}", signing_key_id(&cert)?.to_lowercase());
+        assert_eq!(hkp_lookup(&certs, &fingerprint), [&cert]);
+        assert_eq!(hkp_lookup(&certs, &key_id), [&cert]);
+        assert_eq!(hkp_lookup(&certs, &format!("SIGN@{domain}")), [&cert]);
+        assert!(hkp_lookup(&certs, "0x0123456789ABCDEF").is_empty());
+
+        let index = hkp_index(&certs);
+        let lines: Vec<_> = index.lines().collect();
+        assert_eq!(lines[0], "info:1:1");
+        assert!(lines[1].starts_with(&format!("pub:{}:", cert.fingerprint().to_hex())));
+        assert!(lines[2].starts_with(&format!("uid:PackHub <sign@{domain}>:")));
+
+        Ok(())
+    }
 }
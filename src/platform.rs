@@ -5,7 +5,10 @@ use lenient_semver::parse;
 use regex::Regex;
 use semver::{Version, VersionReq};
 
-use crate::{REQWEST, utils::Dist};
+use crate::{
+    REQWEST,
+    utils::{Arch, Compression, Dist},
+};
 
 static PRE_RELEASE_STRIPER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(\d+)\D").unwrap());
 static APT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"Debian APT.+\((.+)\)"#).unwrap());
@@ -13,6 +16,9 @@ static FEDORA: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r#"libdnf \(Fedora Linux (\d+);"#).unwrap());
 static TUMBLEWEED: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r#"ZYpp.+openSUSE-Tumbleweed"#).unwrap());
+static LIBDNF_ARCH: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"libdnf \(.+; Linux\.(\w+)\)"#).unwrap());
+static ZYPP_ARCH: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"ZYpp.+-(\w+)$"#).unwrap());
 
 /// Detects platform based on the user-agent string of `apt` package manager.
 pub struct AptPlatformDetection {
@@ -52,18 +58,16 @@ impl AptPlatformDetection {
         for (key, value) in map.into_iter() {
             let mut versions = value.into_iter().collect::<Vec<Version>>();
             versions.sort();
-            let requirement;
-
-            if versions.len() > 1 {
-                requirement = VersionReq::parse(&format!(
+            let requirement = if versions.len() > 1 {
+                VersionReq::parse(&format!(
                     ">={}, <={}",
                     versions[0],
                     versions[versions.len() - 1]
                 ))
-                .unwrap();
+                .unwrap()
             } else {
-                requirement = VersionReq::parse(&format!("={}", versions[0])).unwrap();
-            }
+                VersionReq::parse(&format!("={}", versions[0])).unwrap()
+            };
 
             if key.starts_with("ubuntu") {
                 let ver = key.trim_start_matches("ubuntu_");
@@ -141,6 +145,29 @@ pub fn detect_opensuse_tumbleweed(agent: &str) -> bool {
     TUMBLEWEED.is_match(agent)
 }
 
+/// Detect the architecture of the client from the user-agent string of `libdnf` or `zypper`.
+pub fn detect_rpm_arch(agent: &str) -> Option<Arch> {
+    let caps = LIBDNF_ARCH
+        .captures(agent)
+        .or_else(|| ZYPP_ARCH.captures(agent))?;
+
+    caps.get(1)?.as_str().parse().ok()
+}
+
+/// Detect the compression of the RPM metadata supported by the client.
+///
+/// Fedora switched its repositories to zstd in Fedora 36, so older `libdnf`
+/// versions get xz. Other clients like `yum` and `zypper` get gzip, which
+/// every version of them can read.
+pub fn detect_rpm_compression(agent: &str) -> Compression {
+    match get_fedora_version(agent).and_then(|ver| ver.parse::<u32>().ok()) {
+        Some(ver) if ver >= 36 => Compression::Zstd,
+        Some(_) => Compression::Xz,
+        None if LIBDNF_ARCH.is_match(agent) => Compression::Xz,
+        None => Compression::Gzip,
+    }
+}
+
 pub fn detect_rpm_os(agent: &str) -> Option<Dist> {
     if let Some(ver) = get_fedora_version(agent) {
         Some(Dist::fedora(ver))
@@ -221,6 +248,47 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_rpm_arch() {
+        assert_eq!(
+            detect_rpm_arch("libdnf (Fedora Linux 38; container; Linux.x86_64)"),
+            Some(Arch::Amd64)
+        );
+        assert_eq!(
+            detect_rpm_arch("libdnf (Fedora Linux 41; server; Linux.aarch64)"),
+            Some(Arch::Arm64)
+        );
+        assert_eq!(
+            detect_rpm_arch("ZYpp 17.31.15 (curl 8.5.0) openSUSE-Tumbleweed-x86_64"),
+            Some(Arch::Amd64)
+        );
+        assert_eq!(detect_rpm_arch("urlgrabber/3.10 yum/3.4.3"), None);
+    }
+
+    #[test]
+    fn test_rpm_compression() {
+        assert_eq!(
+            detect_rpm_compression("libdnf (Fedora Linux 38; container; Linux.x86_64)"),
+            Compression::Zstd
+        );
+        assert_eq!(
+            detect_rpm_compression("libdnf (Fedora 33; container; Linux.x86_64)"),
+            Compression::Xz
+        );
+        assert_eq!(
+            detect_rpm_compression("libdnf (Fedora Linux 33; container; Linux.x86_64)"),
+            Compression::Xz
+        );
+        assert_eq!(
+            detect_rpm_compression("ZYpp 17.31.15 (curl 8.5.0) openSUSE-Tumbleweed-x86_64"),
+            Compression::Gzip
+        );
+        assert_eq!(
+            detect_rpm_compression("urlgrabber/3.10 yum/3.4.3"),
+            Compression::Gzip
+        );
+    }
+
     #[test]
     fn test_detect_opensuse() {
         assert!(detect_opensuse_tumbleweed(
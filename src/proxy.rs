@@ -0,0 +1,401 @@
+//! Proxying of the release assets served in the indices.
+//!
+//! The packages are served from the package cache when it is enabled, which also supports
+//! the `Range` requests of the clients resuming their downloads. The unmodified packages can
+//! instead be redirected to GitHub or a mirror, so that they don't use the bandwidth of PackHub.
+
+use std::{io, pin::pin, sync::LazyLock};
+
+use anyhow::{Context, Result, bail};
+use axum::{
+    body::{Body, Bytes},
+    extract::Request,
+    http::{HeaderValue, StatusCode, header, request::Parts},
+    response::{IntoResponse, Response},
+};
+use futures_util::{Stream, TryStreamExt, stream::try_unfold};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tower_http::services::ServeFile;
+
+use crate::{
+    REQWEST,
+    cache::{self, BlobCache, CACHE},
+    package::Verification,
+    utils::hashsum,
+};
+
+/// Release asset of a GitHub repository.
+pub struct Asset<'a> {
+    pub owner: &'a str,
+    pub repo: &'a str,
+    pub ver: &'a str,
+    pub file: &'a str,
+}
+
+impl Asset<'_> {
+    /// URL of the asset under the `base` URL of GitHub or its mirror.
+    fn url(&self, base: &str) -> String {
+        let Asset {
+            owner,
+            repo,
+            ver,
+            file,
+        } = self;
+        let base = base.trim_end_matches('/');
+        let mut url = match reqwest::Url::parse(base) {
+            Ok(url) if !url.cannot_be_a_base() => url,
+            _ => return format!("{base}/{owner}/{repo}/releases/download/{ver}/{file}"),
+        };
+
+        // The tags can contain the characters reserved in the URLs
+        url.path_segments_mut()
+            .expect("URL can be a base")
+            .pop_if_empty()
+            .extend([*owner, *repo, "releases", "download"])
+            .extend(ver.split('/'))
+            .push(file);
+
+        url.into()
+    }
+}
+
+/// Redirection of the package downloads, enabled with the `PACKHUB_REDIRECT_DOWNLOADS` variable.
+///
+/// The packages are redirected to the mirror configured with `PACKHUB_REDIRECT_MIRROR`, or to
+/// GitHub. Only the packages of at least `PACKHUB_REDIRECT_MIN_SIZE` MiB are redirected.
+static REDIRECTS: LazyLock<Option<Redirects>> = LazyLock::new(|| {
+    if !dotenvy::var("PACKHUB_REDIRECT_DOWNLOADS").is_ok_and(|v| v == "true") {
+        return None;
+    }
+    let mirror = dotenvy::var("PACKHUB_REDIRECT_MIRROR")
+        .ok()
+        .filter(|mirror| !mirror.is_empty())
+        .unwrap_or_else(|| GITHUB.to_owned());
+    let min_size = dotenvy::var("PACKHUB_REDIRECT_MIN_SIZE")
+        .ok()
+        .and_then(|size| size.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    Some(Redirects {
+        mirror,
+        min_size: min_size * 1024 * 1024,
+    })
+});
+
+const GITHUB: &str = "https://github.com";
+
+struct Redirects {
+    mirror: String,
+    min_size: usize,
+}
+
+impl Redirects {
+    /// Location of the package the `asset` is redirected to, if it is large enough.
+    fn location(&self, asset: &Asset, expected: &Expected) -> Option<String> {
+        (expected.size >= self.min_size).then(|| asset.url(&self.mirror))
+    }
+}
+
+/// Size and SHA-256 hash of an asset recorded in the indices.
+#[derive(Clone, Copy)]
+pub struct Expected<'a> {
+    pub size: usize,
+    pub sha256: &'a str,
+}
+
+/// Request the release `asset` from GitHub.
+pub async fn download(asset: &Asset<'_>) -> Result<reqwest::Response> {
+    let url = asset.url(GITHUB);
+    tracing::trace!("Proxying package from: {}", url);
+
+    let res = REQWEST
+        .get(url)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .context("Error occurred while proxying package")?;
+    tracing::trace!("Proxying package respone: {}", res.status());
+
+    Ok(res)
+}
+
+/// Serve the package of the `asset` from the cache, or from GitHub when it isn't cached.
+///
+/// `prepare` turns the downloaded asset into the served package, e.g. by embedding the
+/// signatures into it. The package is cached after it is checked against the `expected` one.
+/// The packages without `prepare` are redirected instead, when the redirection is enabled.
+pub async fn serve_package<F>(
+    request: Request,
+    asset: Asset<'_>,
+    expected: Expected<'_>,
+    prepare: Option<F>,
+) -> Result<Response>
+where
+    F: FnOnce(&[u8]) -> Result<Vec<u8>>,
+{
+    if prepare.is_none()
+        && let Some(location) = REDIRECTS
+            .as_ref()
+            .and_then(|redirects| redirects.location(&asset, &expected))
+    {
+        tracing::trace!("Redirecting package to: {}", location);
+        return Ok((StatusCode::FOUND, [(header::LOCATION, location)]).into_response());
+    }
+
+    let (request, _) = request.into_parts();
+    if let Some(response) = serve_cached(&request, expected.sha256).await? {
+        return Ok(response);
+    }
+
+    let res = download(&asset).await?;
+
+    let Some(prepare) = prepare else {
+        let Some(cache) = CACHE
+            .as_ref()
+            .filter(|cache| cache.fits(expected.size as u64))
+        else {
+            // The packages which aren't cached are streamed as they are downloaded
+            return Ok(Body::from_stream(verified_stream(res, expected)).into_response());
+        };
+
+        if let Err(e) = cache_download(cache, res, expected).await {
+            tracing::warn!("Failed to cache the package {}: {e}", expected.sha256);
+        } else if let Some(response) = serve_cached(&request, expected.sha256).await? {
+            return Ok(response);
+        }
+
+        // The package is streamed from GitHub again, when it couldn't be served from the cache
+        let res = download(&asset).await?;
+        return Ok(Body::from_stream(verified_stream(res, expected)).into_response());
+    };
+
+    let data = res
+        .bytes()
+        .await
+        .context("Error occurred while proxying package")?;
+    let data = prepare(&data)?;
+    verify(&data, &expected)?;
+
+    if let Some(cache) = CACHE.as_ref() {
+        let temporary = cache.temporary_path();
+        let stored = match tokio::fs::write(&temporary, &data).await {
+            Ok(()) => cache.insert_file(expected.sha256, &temporary, data.len() as u64),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = stored {
+            tracing::warn!("Failed to cache the package {}: {e}", expected.sha256);
+        } else if let Some(response) = serve_cached(&request, expected.sha256).await? {
+            return Ok(response);
+        }
+    }
+
+    Ok(Body::from(data).into_response())
+}
+
+/// Write the body of the `res` to a temporary file of the `cache`, checking it against the
+/// `expected` asset as it is received, and store it in the cache.
+async fn cache_download(
+    cache: &BlobCache,
+    res: reqwest::Response,
+    expected: Expected<'_>,
+) -> Result<()> {
+    let temporary = cache.temporary_path();
+    let written = async {
+        let mut file = tokio::fs::File::create(&temporary).await?;
+        let mut body = pin!(verified_stream(res, expected));
+        while let Some(chunk) = body.try_next().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await
+    }
+    .await;
+
+    match written {
+        Ok(()) => cache.insert_file(expected.sha256, &temporary, expected.size as u64),
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&temporary).await;
+            Err(e.into())
+        }
+    }
+}
+
+/// Expose the status of the verification of the served package with the signature of its
+/// maintainer, as the `X-PackHub-Verification` header of the `response`.
+pub fn with_verification(mut response: Response, verification: Verification) -> Response {
+    response.headers_mut().insert(
+        "x-packhub-verification",
+        HeaderValue::from_static(verification.as_str()),
+    );
+    response
+}
+
+/// Serve the cached package with the `sha256` hash, supporting the `Range` requests.
+///
+/// `None` is returned when the package isn't cached, or was evicted before it was opened.
+async fn serve_cached(request: &Parts, sha256: &str) -> Result<Option<Response>> {
+    let Some(path) = cache::cached(sha256) else {
+        return Ok(None);
+    };
+    let request = Request::from_parts(request.clone(), Body::empty());
+    let response = ServeFile::new(path).try_call(request).await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    Ok(Some(response.map(Body::new)))
+}
+
+/// Check that the `data` is the asset recorded in the indices.
+pub fn verify(data: &[u8], expected: &Expected) -> Result<()> {
+    if data.len() != expected.size {
+        bail!(
+            "Size of the package ({}) doesn't match the indexed one ({})",
+            data.len(),
+            expected.size
+        );
+    }
+    if hashsum::<Sha256>(data) != expected.sha256 {
+        bail!("Hash of the package doesn't match the indexed one");
+    }
+
+    Ok(())
+}
+
+/// Stream the body of the `res`, checking it against the asset recorded in the indices.
+///
+/// The stream fails as soon as the body exceeds the recorded size, or at its end when the
+/// size or the hash don't match, so that the clients don't receive a complete package.
+pub fn verified_stream(
+    res: reqwest::Response,
+    expected: Expected,
+) -> impl Stream<Item = io::Result<Bytes>> + use<> {
+    let size = expected.size;
+    let sha256 = expected.sha256.to_owned();
+    let body = res.bytes_stream().map_err(io::Error::other);
+
+    try_unfold(
+        (body, Sha256::new(), 0),
+        move |(mut body, mut hasher, received)| {
+            let sha256 = sha256.clone();
+            async move {
+                let Some(chunk) = body.try_next().await? else {
+                    let hash = format!("{:x}", hasher.finalize());
+                    if received != size || hash != sha256 {
+                        return Err(io::Error::other(
+                            "Proxied package doesn't match the indexed one",
+                        ));
+                    }
+                    return Ok(None);
+                };
+
+                let received = received + chunk.len();
+                if received > size {
+                    return Err(io::Error::other(
+                        "Proxied package is larger than the indexed one",
+                    ));
+                }
+                hasher.update(&chunk);
+
+                Ok(Some((chunk, (body, hasher, received))))
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http;
+
+    use super::*;
+
+    async fn proxy(data: &'static [u8], expected: Expected<'_>) -> io::Result<Vec<u8>> {
+        let res = reqwest::Response::from(http::Response::new(data));
+        let chunks: Vec<Bytes> = verified_stream(res, expected).try_collect().await?;
+        Ok(chunks.concat())
+    }
+
+    #[tokio::test]
+    async fn test_verification() {
+        let data = b"Package data";
+        let sha256 = hashsum::<Sha256>(data);
+        let expected = || Expected {
+            size: data.len(),
+            sha256: &sha256,
+        };
+
+        assert!(verify(data, &expected()).is_ok());
+        assert!(verify(b"Package dat4", &expected()).is_err());
+        assert!(verify(b"Package data!", &expected()).is_err());
+
+        assert_eq!(proxy(data, expected()).await.unwrap(), data);
+        assert!(proxy(b"Package dat4", expected()).await.is_err());
+        assert!(proxy(b"Package data!", expected()).await.is_err());
+        assert!(proxy(b"Package", expected()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cached_download() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("packhub-proxy-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = BlobCache::open(&dir, 1024)?;
+
+        let data = b"Package data";
+        let sha256 = hashsum::<Sha256>(data);
+        let expected = Expected {
+            size: data.len(),
+            sha256: &sha256,
+        };
+        let response = |data: &'static [u8]| reqwest::Response::from(http::Response::new(data));
+
+        assert!(
+            cache_download(&cache, response(b"Package dat4"), expected)
+                .await
+                .is_err()
+        );
+        assert!(cache.get(&sha256).is_none());
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 0);
+
+        cache_download(&cache, response(data), expected).await?;
+        assert_eq!(std::fs::read(cache.get(&sha256).unwrap())?, data);
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+
+        std::fs::remove_dir_all(dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_redirect_location() {
+        let asset = Asset {
+            owner: "OpenBangla",
+            repo: "OpenBangla-Keyboard",
+            ver: "2.0.0",
+            file: "OpenBangla-Keyboard_2.0.0-ubuntu22.04.deb",
+        };
+        let expected = |size| Expected { size, sha256: "" };
+        let redirects = Redirects {
+            mirror: "https://mirror.example.com/github/".to_owned(),
+            min_size: 1024,
+        };
+
+        assert_eq!(
+            redirects.location(&asset, &expected(1024)).unwrap(),
+            "https://mirror.example.com/github/OpenBangla/OpenBangla-Keyboard/releases/download/2.0.0/OpenBangla-Keyboard_2.0.0-ubuntu22.04.deb"
+        );
+        assert!(redirects.location(&asset, &expected(1023)).is_none());
+        assert_eq!(
+            asset.url(GITHUB),
+            "https://github.com/OpenBangla/OpenBangla-Keyboard/releases/download/2.0.0/OpenBangla-Keyboard_2.0.0-ubuntu22.04.deb"
+        );
+
+        let asset = Asset {
+            ver: "release/v1.0#50%",
+            ..asset
+        };
+        assert_eq!(
+            asset.url(GITHUB),
+            "https://github.com/OpenBangla/OpenBangla-Keyboard/releases/download/release/v1.0%2350%25/OpenBangla-Keyboard_2.0.0-ubuntu22.04.deb"
+        );
+    }
+}
@@ -1,14 +1,25 @@
-use anyhow::{Result, bail};
+use std::collections::HashMap;
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
 use mongodb::Collection;
+use octocrab::models::{ReleaseId, repos::Release};
+use sequoia_openpgp::Cert;
 use tokio::task::JoinSet;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 use crate::{
-    db::PackageMetadata,
-    package::Package,
+    REQWEST,
+    conditional::ClientTag,
+    config::RepositoryConfig,
+    db::{PackageMetadata, ReleaseMetadata},
+    ident::{Owner, Repo, project},
+    package::{Data, Package, Verification},
+    pgp::verify_detached_signature,
     platform::{AptPlatformDetection, detect_rpm_os},
     selector::select_packages,
     state::AppState,
+    utils::Arch,
 };
 
 pub struct Repository {
@@ -16,24 +27,103 @@ pub struct Repository {
     packages: Vec<Package>,
     downloaded: Vec<Package>,
     platform: AptPlatformDetection,
+    release: ReleaseMetadata,
+    release_id: ReleaseId,
+    /// Public key of the upstream maintainer, used to verify the packages.
+    maintainer: Option<Cert>,
+    /// Download URLs of the signatures of the packages, by the name of the packages.
+    signatures: HashMap<String, String>,
+    /// Fingerprint of the key re-signing the packages, if they are re-signed.
+    signed_by: Option<String>,
+    /// Downloaded packages with an invalid signature, recorded so that they aren't downloaded again.
+    invalid: Vec<Package>,
+    /// Whether only the packages verified with the signature of the maintainer are served.
+    required: bool,
 }
 
 impl Repository {
-    pub async fn from_github(owner: String, repo: String, state: &AppState) -> Self {
-        let project = format!("{owner}/{repo}");
+    /// Latest release of the `owner/repo` repository on GitHub.
+    pub async fn latest_release(state: &AppState, owner: &Owner, repo: &Repo) -> Result<Release> {
+        state
+            .github()
+            .repos(&**owner, &**repo)
+            .releases()
+            .get_latest()
+            .await
+            .with_context(|| format!("No release found for {owner}/{repo}"))
+    }
+
+    /// Serve the packages of the latest `release` of the repository.
+    ///
+    /// The stored metadata of its packages is used if they were re-signed by the key with the
+    /// `signed_by` fingerprint, or weren't re-signed when `None`.
+    pub async fn from_release(
+        owner: Owner,
+        repo: Repo,
+        state: &AppState,
+        release: Release,
+        signed_by: Option<String>,
+    ) -> Self {
+        let repository = project(&owner, &repo);
         let collection = state
             .db()
             .database("github")
-            .collection::<PackageMetadata>(&project);
+            .collection::<PackageMetadata>(&repository);
+        let releases = state
+            .db()
+            .database("packhub")
+            .collection::<ReleaseMetadata>("releases");
 
         let mut packages = Vec::new();
-        let release = state
-            .github()
-            .repos(owner, repo)
-            .releases()
-            .get_latest()
-            .await
-            .unwrap();
+
+        // The configuration is only read from GitHub the first time the release is seen
+        let (metadata, changed) =
+            match ReleaseMetadata::retrieve(&releases, &repository, &release.tag_name).await {
+                Ok(Some(mut metadata)) => {
+                    let changed = metadata.update(&release);
+                    (metadata, changed)
+                }
+                result => {
+                    if let Err(e) = result {
+                        error!("Failed to retrieve the release {}: {e}", release.tag_name);
+                    }
+                    let config = RepositoryConfig::from_github(
+                        state.github(),
+                        &owner,
+                        &repo,
+                        &release.tag_name,
+                    )
+                    .await;
+                    let key = config
+                        .maintainer_key(state.github(), &owner, &repo, &release.tag_name)
+                        .await;
+                    (
+                        ReleaseMetadata::new(repository, &release, config, key),
+                        true,
+                    )
+                }
+            };
+
+        if changed && let Err(e) = metadata.store(&releases).await {
+            error!("Failed to save the release {}: {e}", release.tag_name);
+        }
+
+        let config = metadata.config();
+        let maintainer = metadata.maintainer_key();
+        let required = maintainer.is_some() && config.signatures.required;
+
+        // Detached signatures published along with the assets
+        let signatures: HashMap<String, String> = release
+            .assets
+            .iter()
+            .filter_map(|asset| {
+                let name = asset
+                    .name
+                    .strip_suffix(".asc")
+                    .or_else(|| asset.name.strip_suffix(".sig"))?;
+                Some((name.to_owned(), asset.browser_download_url.to_string()))
+            })
+            .collect();
 
         for asset in release.assets {
             let package = Package::detect_package(
@@ -43,11 +133,14 @@ impl Repository {
                 asset.updated_at,
             );
             if let Ok(package) = package {
-                if let Some(metadata) = PackageMetadata::retrieve_from(&collection, &package).await
-                {
-                    package.set_metadata(metadata.data());
+                if required && !signatures.contains_key(&asset.name) {
+                    warn!("Skipping the unsigned package: {:?}", package.file_name());
+                    continue;
+                }
+
+                if load_metadata(&collection, &package, signed_by.as_deref(), required).await {
+                    packages.push(package);
                 }
-                packages.push(package);
             }
         }
 
@@ -58,12 +151,66 @@ impl Repository {
             packages,
             platform,
             downloaded: Vec::new(),
+            release: metadata,
+            release_id: release.id,
+            maintainer,
+            signatures,
+            signed_by,
+            invalid: Vec::new(),
+            required,
         }
     }
 
+    /// Release from which the packages are served.
+    pub fn release(&self) -> &ReleaseMetadata {
+        &self.release
+    }
+
+    /// Date of the last change of the release, either its publication or an upload of its assets.
+    pub fn last_modified(&self) -> DateTime<Utc> {
+        self.packages
+            .iter()
+            .map(|package| *package.creation_date())
+            .chain(self.release.published_at().copied())
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Tag of the indices built for the client detected from the `inputs`.
+    ///
+    /// It changes along with the release, its packages and the key re-signing them, so that
+    /// the indices can be revalidated before the packages are selected and downloaded.
+    pub fn client_tag(&self, inputs: &[&str]) -> ClientTag {
+        let release = [
+            env!("CARGO_PKG_VERSION").to_owned(),
+            self.release_id.to_string(),
+            self.release.tag().to_owned(),
+            self.release
+                .published_at()
+                .map(DateTime::to_rfc3339)
+                .unwrap_or_default(),
+            self.signed_by.clone().unwrap_or_default(),
+        ];
+        let packages = self.packages.iter().map(|package| {
+            format!(
+                "{} {}",
+                package.file_name(),
+                package.creation_date().to_rfc3339()
+            )
+        });
+        let inputs: Vec<String> = release
+            .into_iter()
+            .chain(packages)
+            .chain(inputs.iter().map(|&input| input.to_owned()))
+            .collect();
+
+        ClientTag::new(&inputs)
+    }
+
     pub async fn save_package_metadata(&mut self) {
         for package in &self.downloaded {
-            let Some(metadata) = PackageMetadata::from_package(package) else {
+            let Some(metadata) = PackageMetadata::from_package(package, self.signed_by.as_deref())
+            else {
                 error!(
                     "Metadata was not available for saving the package: {:?}",
                     package.file_name()
@@ -71,7 +218,7 @@ impl Repository {
                 return;
             };
 
-            if let Err(e) = self.collection.insert_one(metadata).await {
+            if let Err(e) = metadata.store(&self.collection).await {
                 error!(
                     "Failed to save metadata for package: {:?}\n Error: {e}",
                     package.file_name()
@@ -80,6 +227,16 @@ impl Repository {
             };
             debug!("Saved metadata for package: {:?}", package.file_name());
         }
+
+        for package in &self.invalid {
+            let metadata = PackageMetadata::invalid(package, self.signed_by.as_deref());
+            if let Err(e) = metadata.store(&self.collection).await {
+                error!(
+                    "Failed to record the invalid package: {:?}\n Error: {e}",
+                    package.file_name()
+                );
+            }
+        }
     }
 
     /// Select packages for apt based distributions.
@@ -98,7 +255,7 @@ impl Repository {
             dist => bail!("Unknown apt distribution {dist}"),
         };
 
-        let packages: Vec<Package> = select_packages(&self.packages, dist)
+        let packages: Vec<Package> = select_packages(&self.packages, dist, None)
             .into_iter()
             .cloned()
             .collect();
@@ -112,14 +269,20 @@ impl Repository {
     ///
     /// The `agent` parameter is the user-agent string of the rpm client.
     ///
+    /// The `arch` parameter is the architecture of the client, if known.
+    ///
     /// It returns a vector of packages that are compatible with the given agent.
     ///
     /// It also downloads the selected packages if the metadata is not available.
-    pub async fn select_package_rpm(&mut self, agent: &str) -> Result<Vec<Package>> {
+    pub async fn select_package_rpm(
+        &mut self,
+        agent: &str,
+        arch: Option<Arch>,
+    ) -> Result<Vec<Package>> {
         let Some(dist) = detect_rpm_os(agent) else {
             bail!("Unknown RPM distribution agent: {agent}");
         };
-        let packages: Vec<Package> = select_packages(&self.packages, dist)
+        let packages: Vec<Package> = select_packages(&self.packages, dist, arch)
             .into_iter()
             .cloned()
             .collect();
@@ -135,9 +298,19 @@ impl Repository {
 
         for package in packages {
             if !package.is_metadata_available() {
+                let verifier = self
+                    .maintainer
+                    .clone()
+                    .zip(self.signatures.get(package.file_name()).cloned());
                 runner.spawn(async move {
                     debug!("Downloading package: {:?}", package.file_name());
-                    package.download().await.and_then(|_| Ok(package))
+                    package.download().await?;
+
+                    if let Some((maintainer, signature)) = verifier {
+                        return verify_package(package, &maintainer, &signature).await;
+                    }
+
+                    Ok(package)
                 });
             } else {
                 debug!("Package metadata available: {:?}", package.file_name());
@@ -151,6 +324,18 @@ impl Repository {
             };
 
             let package = res?;
+            match package.verification() {
+                Verification::Invalid => {
+                    self.invalid.push(package);
+                    continue;
+                }
+                // The signature couldn't be fetched, so the package is verified again later
+                Verification::Unverified if self.required => {
+                    warn!("Skipping the unverified package: {:?}", package.file_name());
+                    continue;
+                }
+                _ => {}
+            }
 
             debug!("Downloaded package: {:?}", package.file_name());
 
@@ -163,3 +348,212 @@ impl Repository {
         Ok(result)
     }
 }
+
+/// Set the stored metadata of the `package`, re-signed by the key with the `signed_by`
+/// fingerprint if any.
+///
+/// The metadata of the unverified packages isn't used when the signatures are `required`,
+/// so that they are downloaded and verified again. `false` is returned if the package has
+/// an invalid signature, as it mustn't be served.
+async fn load_metadata(
+    collection: &Collection<PackageMetadata>,
+    package: &Package,
+    signed_by: Option<&str>,
+    required: bool,
+) -> bool {
+    let Some(metadata) = PackageMetadata::retrieve_from(collection, package, signed_by).await
+    else {
+        return true;
+    };
+
+    match metadata.verification() {
+        Verification::Invalid => {
+            warn!(
+                "Skipping the package with an invalid signature: {:?}",
+                package.file_name()
+            );
+            false
+        }
+        Verification::Unverified if required => true,
+        verification => {
+            package.set_verification(verification);
+            package.set_metadata(metadata.data());
+            true
+        }
+    }
+}
+
+/// Verify the downloaded package with its detached signature made by the `maintainer`.
+///
+/// The package is marked as invalid if the signature is invalid, so that it isn't served.
+/// It stays unverified when the signature can't be fetched.
+async fn verify_package(package: Package, maintainer: &Cert, signature: &str) -> Result<Package> {
+    let signature = match fetch_signature(signature).await {
+        Ok(signature) => signature,
+        Err(e) => {
+            warn!(
+                "Failed to fetch the signature of the package: {:?}\n Error: {e}",
+                package.file_name()
+            );
+            return Ok(package);
+        }
+    };
+
+    let Data::Package(data) = package.data() else {
+        bail!("Package data is not available");
+    };
+
+    let verification = check_signature(&package, &data, &signature, maintainer);
+    package.set_verification(verification);
+
+    Ok(package)
+}
+
+async fn fetch_signature(url: &str) -> Result<Vec<u8>> {
+    let signature = REQWEST
+        .get(url)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())?
+        .bytes()
+        .await?;
+
+    Ok(signature.to_vec())
+}
+
+/// Check the detached `signature` of the `data` of the `package` made by the `maintainer`.
+fn check_signature(
+    package: &Package,
+    data: &[u8],
+    signature: &[u8],
+    maintainer: &Cert,
+) -> Verification {
+    if let Err(e) = verify_detached_signature(data, signature, maintainer) {
+        warn!(
+            "Skipping the package with an invalid signature: {:?}\n Error: {e}",
+            package.file_name()
+        );
+        return Verification::Invalid;
+    }
+
+    debug!(
+        "Verified the signature of package: {:?}",
+        package.file_name()
+    );
+    Verification::Verified
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::read;
+
+    use sequoia_openpgp::{cert::CertBuilder, crypto::Password};
+    use testcontainers_modules::{mongo::Mongo, testcontainers::runners::AsyncRunner};
+
+    use super::*;
+    use crate::{
+        apt::DebianPackage, db::tests::setup_mongodb, package::tests::package_with_ver,
+        pgp::detached_sign_binary,
+    };
+
+    fn hello_package() -> Package {
+        let package = package_with_ver("packhub-hello_1.0.0_amd64.deb", "1.0.0");
+        package.set_package_data(read("data/packhub-hello_1.0.0_amd64.deb").unwrap());
+        package
+    }
+
+    #[test]
+    fn test_signature_check() {
+        let passphrase: Password = "passphrase".into();
+        let generate = || {
+            CertBuilder::new()
+                .set_password(Some(passphrase.clone()))
+                .add_signing_subkey()
+                .generate()
+                .unwrap()
+                .0
+        };
+        let (maintainer, other) = (generate(), generate());
+
+        let package = hello_package();
+        let Data::Package(data) = package.data() else {
+            panic!("Package data is not available");
+        };
+
+        let signature = detached_sign_binary(&data, &maintainer, &passphrase).unwrap();
+        assert_eq!(
+            check_signature(&package, &data, &signature, &maintainer),
+            Verification::Verified
+        );
+
+        let signature = detached_sign_binary(&data, &other, &passphrase).unwrap();
+        assert_eq!(
+            check_signature(&package, &data, &signature, &maintainer),
+            Verification::Invalid
+        );
+
+        let signature = detached_sign_binary(b"other data", &maintainer, &passphrase).unwrap();
+        assert_eq!(
+            check_signature(&package, &data, &signature, &maintainer),
+            Verification::Invalid
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_signature() {
+        let maintainer = CertBuilder::new()
+            .add_signing_subkey()
+            .generate()
+            .unwrap()
+            .0;
+
+        // The package stays unverified when its signature can't be fetched
+        let package = verify_package(hello_package(), &maintainer, "http://127.0.0.1:1/hello.sig")
+            .await
+            .unwrap();
+        assert_eq!(package.verification(), Verification::Unverified);
+    }
+
+    #[tokio::test]
+    async fn test_stored_verification() {
+        let container = Mongo::default().start().await.unwrap();
+        let client = setup_mongodb(&container).await;
+        let collection = client
+            .database("github")
+            .collection::<PackageMetadata>("test");
+
+        // The packages with an invalid signature are skipped without downloading them again
+        let package = hello_package();
+        PackageMetadata::invalid(&package, None)
+            .store(&collection)
+            .await
+            .unwrap();
+        assert!(!load_metadata(&collection, &package, None, false).await);
+
+        let package = hello_package();
+        DebianPackage::from_package(&package).unwrap();
+        let metadata = PackageMetadata::from_package(&package, None).unwrap();
+        metadata.store(&collection).await.unwrap();
+
+        let package = package_with_ver("packhub-hello_1.0.0_amd64.deb", "1.0.0");
+        assert!(load_metadata(&collection, &package, None, false).await);
+        assert!(package.is_metadata_available());
+        assert_eq!(package.verification(), Verification::Unverified);
+
+        // The unverified packages are verified again once the signatures are required
+        let package = package_with_ver("packhub-hello_1.0.0_amd64.deb", "1.0.0");
+        assert!(load_metadata(&collection, &package, None, true).await);
+        assert!(!package.is_metadata_available());
+
+        let package = hello_package();
+        DebianPackage::from_package(&package).unwrap();
+        package.set_verification(Verification::Verified);
+        let metadata = PackageMetadata::from_package(&package, None).unwrap();
+        metadata.store(&collection).await.unwrap();
+
+        let package = package_with_ver("packhub-hello_1.0.0_amd64.deb", "1.0.0");
+        assert!(load_metadata(&collection, &package, None, true).await);
+        assert!(package.is_metadata_available());
+        assert_eq!(package.verification(), Verification::Verified);
+    }
+}
@@ -0,0 +1,226 @@
+//! SQLite databases of the repository metadata.
+//!
+//! `yum` prefers them over the XML metadata. The schema follows the one of `createrepo_c`.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use rusqlite::{Connection, MAIN_DB, Transaction, params};
+
+use super::package::{Dependency, RPMPackage};
+
+pub const DATABASE_VERSION: u32 = 10;
+
+const PRIMARY_SCHEMA: &str = "
+CREATE TABLE db_info (dbversion INTEGER, checksum TEXT);
+CREATE TABLE packages (pkgKey INTEGER PRIMARY KEY, pkgId TEXT, name TEXT, arch TEXT,
+    version TEXT, epoch TEXT, release TEXT, summary TEXT, description TEXT, url TEXT,
+    time_file INTEGER, time_build INTEGER, rpm_license TEXT, rpm_vendor TEXT, rpm_group TEXT,
+    rpm_buildhost TEXT, rpm_sourcerpm TEXT, rpm_header_start INTEGER, rpm_header_end INTEGER,
+    rpm_packager TEXT, size_package INTEGER, size_installed INTEGER, size_archive INTEGER,
+    location_href TEXT, location_base TEXT, checksum_type TEXT);
+CREATE TABLE files (name TEXT, type TEXT, pkgKey INTEGER);
+CREATE TABLE requires (name TEXT, flags TEXT, epoch TEXT, version TEXT, release TEXT,
+    pkgKey INTEGER, pre BOOLEAN DEFAULT FALSE);
+CREATE TABLE provides (name TEXT, flags TEXT, epoch TEXT, version TEXT, release TEXT, pkgKey INTEGER);
+CREATE TABLE conflicts (name TEXT, flags TEXT, epoch TEXT, version TEXT, release TEXT, pkgKey INTEGER);
+CREATE TABLE obsoletes (name TEXT, flags TEXT, epoch TEXT, version TEXT, release TEXT, pkgKey INTEGER);
+CREATE TABLE suggests (name TEXT, flags TEXT, epoch TEXT, version TEXT, release TEXT, pkgKey INTEGER);
+CREATE TABLE enhances (name TEXT, flags TEXT, epoch TEXT, version TEXT, release TEXT, pkgKey INTEGER);
+CREATE TABLE recommends (name TEXT, flags TEXT, epoch TEXT, version TEXT, release TEXT, pkgKey INTEGER);
+CREATE TABLE supplements (name TEXT, flags TEXT, epoch TEXT, version TEXT, release TEXT, pkgKey INTEGER);
+CREATE INDEX packagename ON packages (name);
+CREATE INDEX packageId ON packages (pkgId);
+CREATE INDEX filenames ON files (name);
+CREATE INDEX pkgfiles ON files (pkgKey);
+CREATE INDEX pkgrequires ON requires (pkgKey);
+CREATE INDEX requiresname ON requires (name);
+CREATE INDEX pkgprovides ON provides (pkgKey);
+CREATE INDEX providesname ON provides (name);
+CREATE INDEX pkgconflicts ON conflicts (pkgKey);
+CREATE INDEX pkgobsoletes ON obsoletes (pkgKey);
+";
+
+const FILELISTS_SCHEMA: &str = "
+CREATE TABLE db_info (dbversion INTEGER, checksum TEXT);
+CREATE TABLE packages (pkgKey INTEGER PRIMARY KEY, pkgId TEXT);
+CREATE TABLE filelist (pkgKey INTEGER, dirname TEXT, filenames TEXT, filetypes TEXT);
+CREATE INDEX keyfile ON filelist (pkgKey);
+CREATE INDEX pkgId ON packages (pkgId);
+CREATE INDEX dirnames ON filelist (dirname);
+";
+
+const OTHER_SCHEMA: &str = "
+CREATE TABLE db_info (dbversion INTEGER, checksum TEXT);
+CREATE TABLE packages (pkgKey INTEGER PRIMARY KEY, pkgId TEXT);
+CREATE TABLE changelog (pkgKey INTEGER, author TEXT, date INTEGER, changelog TEXT);
+CREATE INDEX keychange ON changelog (pkgKey);
+CREATE INDEX pkgId ON packages (pkgId);
+";
+
+/// Build the `primary.sqlite` database.
+///
+/// `checksum` is the checksum of the compressed `primary.xml` it is generated alongside.
+pub fn primary_database(packages: &[RPMPackage], checksum: &str) -> Result<Vec<u8>> {
+    create_database(PRIMARY_SCHEMA, checksum, |tx| {
+        for (key, package) in packages.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO packages VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12,
+                    ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, NULL, 'sha256')",
+                params![
+                    key,
+                    package.sha256,
+                    package.name,
+                    package.arch,
+                    package.version,
+                    package.epoch.to_string(),
+                    package.release,
+                    package.summary,
+                    package.description,
+                    package.url,
+                    package.pkg_time,
+                    package.build_time,
+                    package.license,
+                    package.vendor,
+                    package.group,
+                    package.build_host,
+                    package.source,
+                    package.header_start,
+                    package.header_end,
+                    package.packager,
+                    package.pkg_size,
+                    package.installed_size,
+                    package.archive_size,
+                    package.location,
+                ],
+            )?;
+
+            let dependencies = [
+                ("requires", &package.requires),
+                ("provides", &package.provides),
+                ("conflicts", &package.conflicts),
+                ("obsoletes", &package.obsoletes),
+                ("suggests", &package.suggests),
+                ("enhances", &package.enhances),
+                ("recommends", &package.recommends),
+                ("supplements", &package.supplements),
+            ];
+
+            for (table, dependencies) in dependencies {
+                insert_dependencies(tx, table, key, dependencies)?;
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Build the `filelists.sqlite` database.
+///
+/// `checksum` is the checksum of the compressed `filelists.xml` it is generated alongside.
+pub fn filelists_database(packages: &[RPMPackage], checksum: &str) -> Result<Vec<u8>> {
+    create_database(FILELISTS_SCHEMA, checksum, |tx| {
+        for (key, package) in packages.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO packages VALUES (?1, ?2)",
+                params![key, package.sha256],
+            )?;
+
+            // Files are grouped by their directories
+            let mut directories: BTreeMap<&str, (Vec<&str>, String)> = BTreeMap::new();
+            for file in package.files.iter() {
+                let (dir, name) = file.path.rsplit_once('/').unwrap_or(("", &file.path));
+                let (names, types) = directories.entry(dir).or_default();
+                names.push(name);
+                types.push(if file.dir { 'd' } else { 'f' });
+            }
+
+            for (dir, (names, types)) in directories {
+                tx.execute(
+                    "INSERT INTO filelist VALUES (?1, ?2, ?3, ?4)",
+                    params![key, dir, names.join("/"), types],
+                )?;
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Build the `other.sqlite` database.
+///
+/// `checksum` is the checksum of the compressed `other.xml` it is generated alongside.
+pub fn other_database(packages: &[RPMPackage], checksum: &str) -> Result<Vec<u8>> {
+    create_database(OTHER_SCHEMA, checksum, |tx| {
+        for (key, package) in packages.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO packages VALUES (?1, ?2)",
+                params![key, package.sha256],
+            )?;
+
+            for changelog in package.recent_changelogs() {
+                tx.execute(
+                    "INSERT INTO changelog VALUES (?1, ?2, ?3, ?4)",
+                    params![key, changelog.author, changelog.date, changelog.text],
+                )?;
+            }
+        }
+
+        Ok(())
+    })
+}
+
+fn create_database(
+    schema: &str,
+    checksum: &str,
+    insert: impl FnOnce(&Transaction) -> Result<()>,
+) -> Result<Vec<u8>> {
+    let mut db = Connection::open_in_memory()?;
+    db.execute_batch(schema)?;
+
+    let tx = db.transaction()?;
+    tx.execute(
+        "INSERT INTO db_info VALUES (?1, ?2)",
+        params![DATABASE_VERSION, checksum],
+    )?;
+    insert(&tx)?;
+    tx.commit()?;
+
+    Ok(db.serialize(MAIN_DB)?.to_vec())
+}
+
+fn insert_dependencies(
+    tx: &Transaction,
+    table: &str,
+    key: usize,
+    dependencies: &[Dependency],
+) -> Result<()> {
+    let mut statement = tx.prepare(&format!(
+        "INSERT INTO {table} (name, flags, epoch, version, release, pkgKey)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)"
+    ))?;
+
+    for dependency in dependencies {
+        if dependency.version.is_empty() {
+            statement.execute(params![
+                dependency.dependency,
+                None::<&str>,
+                None::<&str>,
+                None::<&str>,
+                None::<&str>,
+                key
+            ])?;
+        } else {
+            statement.execute(params![
+                dependency.dependency,
+                dependency.condition,
+                dependency.epoch(),
+                dependency.ver(),
+                dependency.rel(),
+                key
+            ])?;
+        }
+    }
+
+    Ok(())
+}
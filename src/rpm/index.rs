@@ -1,10 +1,17 @@
+use anyhow::Result;
 use askama::Template;
+use chrono::{DateTime, Utc};
 use sha2::Sha256;
-use zstd::encode_all;
 
-use crate::utils::hashsum;
+use crate::{
+    config::AdvisoryType,
+    utils::{Compression, hashsum},
+};
 
-use super::package::RPMPackage;
+use super::{
+    database::{DATABASE_VERSION, filelists_database, other_database, primary_database},
+    package::RPMPackage,
+};
 
 #[derive(Template)]
 #[template(path = "primary.xml")]
@@ -24,15 +31,42 @@ struct Other<'a> {
     packages: &'a [RPMPackage],
 }
 
+#[derive(Template)]
+#[template(path = "updateinfo.xml")]
+struct UpdateInfo<'a> {
+    advisories: &'a [Advisory<'a>],
+}
+
 #[derive(Template)]
 #[template(path = "repomd.xml")]
 struct RepoMD {
     primary: Metadata,
     filelists: Metadata,
     other: Metadata,
+    updateinfo: Metadata,
+    databases: Option<Databases>,
+    extension: &'static str,
+    database_version: u32,
     timestamp: i64,
 }
 
+struct Databases {
+    primary: Metadata,
+    filelists: Metadata,
+    other: Metadata,
+}
+
+/// Advisory of a release, published in the `updateinfo.xml`.
+pub struct Advisory<'a> {
+    pub id: String,
+    pub title: &'a str,
+    pub kind: AdvisoryType,
+    pub description: &'a str,
+    pub issued: DateTime<Utc>,
+    /// Packages of the release.
+    pub packages: Vec<&'a RPMPackage>,
+}
+
 struct Metadata {
     sha256: String,
     open_sha256: String,
@@ -55,10 +89,60 @@ pub fn get_other_index(packages: &[RPMPackage]) -> String {
     list.render().unwrap()
 }
 
-pub fn get_repomd_index(packages: &[RPMPackage]) -> String {
-    let primary = get_primary_index(packages);
-    let filelists = get_filelists_index(packages);
-    let other = get_other_index(packages);
+pub fn get_updateinfo_index(advisories: &[Advisory]) -> String {
+    let updateinfo = UpdateInfo { advisories };
+    updateinfo.render().unwrap()
+}
+
+/// Build the `primary.sqlite` database for the clients of `compression`.
+pub fn get_primary_database(packages: &[RPMPackage], compression: Compression) -> Result<Vec<u8>> {
+    let primary = compression.compress(get_primary_index(packages).as_bytes());
+    primary_database(packages, &hashsum::<Sha256>(&primary))
+}
+
+/// Build the `filelists.sqlite` database for the clients of `compression`.
+pub fn get_filelists_database(
+    packages: &[RPMPackage],
+    compression: Compression,
+) -> Result<Vec<u8>> {
+    let filelists = compression.compress(get_filelists_index(packages).as_bytes());
+    filelists_database(packages, &hashsum::<Sha256>(&filelists))
+}
+
+/// Build the `other.sqlite` database for the clients of `compression`.
+pub fn get_other_database(packages: &[RPMPackage], compression: Compression) -> Result<Vec<u8>> {
+    let other = compression.compress(get_other_index(packages).as_bytes());
+    other_database(packages, &hashsum::<Sha256>(&other))
+}
+
+/// Build the `repomd.xml` index.
+///
+/// The metadata files are compressed with `compression`, and the SQLite
+/// databases are included when `databases` is set.
+pub fn get_repomd_index(
+    packages: &[RPMPackage],
+    advisories: &[Advisory],
+    compression: Compression,
+    databases: bool,
+) -> Result<String> {
+    let primary = Metadata::create(get_primary_index(packages).as_bytes(), compression);
+    let filelists = Metadata::create(get_filelists_index(packages).as_bytes(), compression);
+    let other = Metadata::create(get_other_index(packages).as_bytes(), compression);
+    let updateinfo = Metadata::create(get_updateinfo_index(advisories).as_bytes(), compression);
+
+    let databases = if databases {
+        let primary = primary_database(packages, &primary.sha256)?;
+        let filelists = filelists_database(packages, &filelists.sha256)?;
+        let other = other_database(packages, &other.sha256)?;
+
+        Some(Databases {
+            primary: Metadata::create(&primary, compression),
+            filelists: Metadata::create(&filelists, compression),
+            other: Metadata::create(&other, compression),
+        })
+    } else {
+        None
+    };
 
     // Find the latest date from the list of packages
     let mut timestamp = 0;
@@ -68,18 +152,26 @@ pub fn get_repomd_index(packages: &[RPMPackage]) -> String {
         }
     }
 
-    let repomd = RepoMD::create(primary, filelists, other, timestamp);
-
-    repomd.render().unwrap()
+    let repomd = RepoMD {
+        primary,
+        filelists,
+        other,
+        updateinfo,
+        databases,
+        extension: compression.extension(),
+        database_version: DATABASE_VERSION,
+        timestamp,
+    };
+
+    Ok(repomd.render()?)
 }
 
 impl Metadata {
-    /// Create the metadata of the `content`.
-    fn create(content: String) -> Metadata {
-        let data = content.as_bytes();
+    /// Create the metadata of the `data` compressed with `compression`.
+    fn create(data: &[u8], compression: Compression) -> Metadata {
         let open_size = data.len();
         let open_sha256 = hashsum::<Sha256>(data);
-        let compressed = encode_all(data, 0).unwrap();
+        let compressed = compression.compress(data);
         let size = compressed.len();
         let sha256 = hashsum::<Sha256>(&compressed);
 
@@ -92,21 +184,6 @@ impl Metadata {
     }
 }
 
-impl RepoMD {
-    fn create(primary: String, filelists: String, other: String, timestamp: i64) -> RepoMD {
-        let primary = Metadata::create(primary);
-        let filelists = Metadata::create(filelists);
-        let other = Metadata::create(other);
-
-        RepoMD {
-            primary,
-            filelists,
-            other,
-            timestamp,
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use std::fs::read;
@@ -114,8 +191,21 @@ mod tests {
     use chrono::DateTime;
     use insta::assert_snapshot;
 
-    use crate::package::{tests::package_with_ver, Package};
     use super::*;
+    use crate::package::{Package, tests::package_with_ver};
+
+    const NOTES: &str = "- Fixed the crash when switching the layouts";
+
+    fn advisory(packages: &[RPMPackage]) -> Advisory<'_> {
+        Advisory {
+            id: "OpenBangla-Keyboard-2.0.0".to_owned(),
+            title: "OpenBangla Keyboard 2.0.0",
+            kind: AdvisoryType::detect(NOTES),
+            description: NOTES,
+            issued: DateTime::UNIX_EPOCH,
+            packages: packages.iter().collect(),
+        }
+    }
 
     #[test]
     fn test_rpm_indices() {
@@ -131,7 +221,9 @@ mod tests {
 
         assert_snapshot!(get_other_index(&packages));
 
-        assert_snapshot!(get_repomd_index(&packages));
+        assert_snapshot!(
+            get_repomd_index(&packages, &[advisory(&packages)], Compression::Zstd, false).unwrap()
+        );
     }
 
     #[test]
@@ -178,6 +270,84 @@ mod tests {
         assert_snapshot!(get_primary_index(&packages));
         assert_snapshot!(get_filelists_index(&packages));
         assert_snapshot!(get_other_index(&packages));
-        assert_snapshot!(get_repomd_index(&packages));
+        assert_snapshot!(
+            get_repomd_index(&packages, &[advisory(&packages)], Compression::Zstd, false).unwrap()
+        );
+        assert_snapshot!(get_updateinfo_index(&[advisory(&packages)]));
+    }
+
+    #[test]
+    fn test_advisories_of_releases() {
+        let package = package_with_ver("fastfetch-linux-amd64.rpm", "2.40.3");
+        package.set_package_data(read("data/fastfetch-linux-amd64.rpm").unwrap());
+        let latest = [RPMPackage::from_package(&package).unwrap()];
+
+        let package = package_with_ver("fastfetch-linux-amd64.rpm", "2.40.2");
+        package.set_package_data(read("data/fastfetch-linux-amd64.rpm").unwrap());
+        let previous = [RPMPackage::from_package(&package).unwrap()];
+
+        let notes = "- Fixes CVE-2025-0001";
+        let advisories = [
+            advisory(&latest),
+            Advisory {
+                id: "fastfetch-2.40.2".to_owned(),
+                title: "fastfetch 2.40.2",
+                kind: AdvisoryType::detect(notes),
+                description: notes,
+                issued: DateTime::UNIX_EPOCH,
+                packages: previous.iter().collect(),
+            },
+        ];
+
+        let updateinfo = get_updateinfo_index(&advisories);
+        assert_eq!(updateinfo.matches("<update ").count(), 2);
+        assert!(updateinfo.contains(r#"type="security""#));
+        assert!(updateinfo.contains("<id>OpenBangla-Keyboard-2.0.0</id>"));
+        assert!(updateinfo.contains("<id>fastfetch-2.40.2</id>"));
+        assert_eq!(updateinfo.matches("<package ").count(), 2);
+    }
+
+    #[test]
+    fn test_sqlite_databases() {
+        let package = package_with_ver("fastfetch-linux-amd64.rpm", "2.40.3");
+        let data = read("data/fastfetch-linux-amd64.rpm").unwrap();
+        package.set_package_data(data);
+        let packages = vec![RPMPackage::from_package(&package).unwrap()];
+
+        let repomd =
+            get_repomd_index(&packages, &[advisory(&packages)], Compression::Gzip, true).unwrap();
+        assert!(repomd.contains("repodata/primary.xml.gz"));
+        assert!(repomd.contains("repodata/primary.sqlite.gz"));
+        assert!(repomd.contains("repodata/filelists.sqlite.gz"));
+        assert!(repomd.contains("repodata/other.sqlite.gz"));
+
+        let primary = Compression::Gzip.compress(get_primary_index(&packages).as_bytes());
+        let checksum = hashsum::<Sha256>(&primary);
+
+        let path = std::env::temp_dir().join("packhub-primary.sqlite");
+        let database = get_primary_database(&packages, Compression::Gzip).unwrap();
+        std::fs::write(&path, database).unwrap();
+        let db = rusqlite::Connection::open(&path).unwrap();
+
+        let info: (u32, String) = db
+            .query_row("SELECT dbversion, checksum FROM db_info", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(info, (DATABASE_VERSION, checksum));
+
+        let (name, arch): (String, String) = db
+            .query_row("SELECT name, arch FROM packages", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!((name.as_str(), arch.as_str()), ("fastfetch", "x86_64"));
+
+        let provides: u32 = db
+            .query_row("SELECT COUNT(*) FROM provides", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(provides as usize, packages[0].provides.len());
+
+        std::fs::remove_file(path).unwrap();
     }
 }
@@ -1,5 +1,6 @@
+mod database;
 mod index;
 mod package;
 mod routes;
 
-pub use self::routes::rpm_routes;
+pub use self::{package::sign_packages, routes::rpm_routes};
@@ -1,14 +1,26 @@
+use std::{cell::RefCell, fmt::Debug, io};
+
 use anyhow::{Context, Result, bail};
-use rpm::{DependencyFlags, FileMode, IndexSignatureTag};
+use rpm::{
+    ChangelogEntry, DependencyFlags, FileMode, IndexSignatureTag, Timestamp,
+    signature::{AlgorithmType, Signing},
+};
+use sequoia_openpgp::{Packet, parse::Parse, types::PublicKeyAlgorithm};
 use serde::{Deserialize, Serialize};
 use serde_json::{from_str, to_string};
 use sha2::Sha256;
 
 use crate::{
+    cache,
     package::{Data, Package},
-    utils::hashsum,
+    utils::{Arch, hashsum},
 };
 
+const DEFAULT_CHANGELOG_LIMIT: usize = 10;
+
+/// Function creating a binary signature of an RPM header.
+pub type SignHeader<'a> = dyn Fn(&[u8]) -> Result<Vec<u8>> + 'a;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RPMPackage {
     pub name: String,
@@ -25,17 +37,29 @@ pub struct RPMPackage {
     pub build_time: u64,
     pub build_host: Option<String>,
     pub source: Option<String>,
+    pub packager: Option<String>,
     pub provides: Vec<Dependency>,
     pub requires: Vec<Dependency>,
+    pub conflicts: Vec<Dependency>,
+    pub obsoletes: Vec<Dependency>,
+    pub recommends: Vec<Dependency>,
+    pub suggests: Vec<Dependency>,
+    pub supplements: Vec<Dependency>,
+    pub enhances: Vec<Dependency>,
     pub sha256: String,
     pub header_start: u64,
     pub header_end: u64,
     pub files: Vec<File>,
+    /// All the changelog entries in chronological order.
+    pub changelogs: Vec<Changelog>,
     pub pkg_size: usize,
     pub installed_size: u64,
     pub archive_size: u64,
     pub location: String,
     pub pkg_time: i64,
+    /// Signature of the header inserted by PackHub, if the package was re-signed.
+    #[serde(default)]
+    pub signature: Option<Vec<u8>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -45,20 +69,82 @@ pub struct Dependency {
     pub condition: String,
 }
 
+impl Dependency {
+    fn new(dependency: rpm::Dependency) -> Self {
+        Dependency {
+            dependency: dependency.name,
+            version: dependency.version,
+            condition: flag_to_condition(dependency.flags),
+        }
+    }
+
+    /// Epoch of the dependency version. It is `0` when not specified.
+    pub fn epoch(&self) -> &str {
+        match self.version.split_once(':') {
+            Some((epoch, _)) => epoch,
+            None => "0",
+        }
+    }
+
+    /// Version of the dependency without the epoch and release.
+    pub fn ver(&self) -> &str {
+        let version = self.without_epoch();
+        version.split_once('-').map_or(version, |(v, _)| v)
+    }
+
+    /// Release of the dependency version, if specified.
+    pub fn rel(&self) -> Option<&str> {
+        self.without_epoch().split_once('-').map(|(_, r)| r)
+    }
+
+    fn without_epoch(&self) -> &str {
+        self.version
+            .split_once(':')
+            .map_or(&self.version, |(_, v)| v)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct File {
     pub path: String,
     pub dir: bool,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Changelog {
+    pub author: String,
+    pub date: u64,
+    pub text: String,
+}
+
 impl RPMPackage {
     /// Parse the package and return the RPM package.
     ///
     /// Also sets the metadata to the package.
     pub fn from_package(package: &Package) -> Result<RPMPackage> {
+        Self::parse(package, None)
+    }
+
+    /// Parse the package after replacing its header signature with the one made by `sign`.
+    ///
+    /// The checksum and header range describe the re-signed package, which is served
+    /// by the package proxy with [`resign_package`].
+    pub fn from_package_signed(package: &Package, sign: &SignHeader<'_>) -> Result<RPMPackage> {
+        Self::parse(package, Some(sign))
+    }
+
+    fn parse(package: &Package, sign: Option<&SignHeader<'_>>) -> Result<RPMPackage> {
         // If the metadata is already available, then build the RPMPackage from it
         if let Data::Metadata(metadata) = package.data() {
-            let rpm: RPMPackage = from_str(&metadata).context("Error while loading RPMPackage from saved Package metadata")?;
+            let rpm: RPMPackage = from_str(&metadata)
+                .context("Error while loading RPMPackage from saved Package metadata")?;
+            // The original package can't be served as the re-signed one, nor the other way round
+            if rpm.signature.is_some() != sign.is_some() {
+                bail!(
+                    "Metadata of {:?} was created with another signing mode",
+                    package.file_name()
+                );
+            }
             return Ok(rpm);
         }
 
@@ -66,20 +152,49 @@ impl RPMPackage {
             bail!("Data isn't loaded in package");
         };
 
-        let mut data = data.as_slice();
+        let package_data = data;
+        let mut data = package_data.as_slice();
         // Calculate these before the data slice is mutated
         let pkg_size = data.len();
         let sha256 = hashsum::<Sha256>(data);
 
-        let rpm = rpm::Package::parse(&mut data)
+        let mut rpm = rpm::Package::parse(&mut data)
             .context("Unable to parse the package using rpm parser crate")?;
+
+        // The archive size is only recorded in the original signature header
+        let archive_size = rpm
+            .metadata
+            .signature
+            .get_entry_data_as_u64(IndexSignatureTag::RPMSIGTAG_LONGARCHIVESIZE)
+            .or_else(|_e| {
+                rpm.metadata
+                    .signature
+                    .get_entry_data_as_u32(IndexSignatureTag::RPMSIGTAG_PAYLOADSIZE)
+                    .map(|v| v as u64)
+            })?;
+
+        let (pkg_size, sha256, signature) = match sign {
+            Some(sign) => {
+                let signature = sign_package(&mut rpm, sign)?;
+                let mut signed = Vec::new();
+                rpm.write(&mut signed)?;
+                let sha256 = hashsum::<Sha256>(&signed);
+                cache::store(&sha256, &signed);
+                (signed.len(), sha256, Some(signature))
+            }
+            None => {
+                cache::store(&sha256, &package_data);
+                (pkg_size, sha256, None)
+            }
+        };
+
         let header = rpm.metadata;
 
         let name = header.get_name()?.to_owned();
         let epoch = header.get_epoch().unwrap_or(0);
         let version = header.get_version()?.to_owned();
         let release = header.get_release()?.to_owned();
-        let arch = header.get_arch()?.to_owned();
+        let arch = normalize_arch(header.get_arch()?);
         let vendor = header.get_vendor().ok().map(|v| v.to_owned());
         let url = header.get_url().ok().map(|v| v.to_owned());
         let license = header.get_license().ok().map(|v| v.to_owned());
@@ -93,24 +208,13 @@ impl RPMPackage {
         let header_start = range.header;
         let header_end = range.payload;
         let installed_size = header.get_installed_size()?;
-        let archive_size = header
-            .signature
-            .get_entry_data_as_u64(IndexSignatureTag::RPMSIGTAG_LONGARCHIVESIZE)
-            .or_else(|_e| {
-                header
-                    .signature
-                    .get_entry_data_as_u32(IndexSignatureTag::RPMSIGTAG_PAYLOADSIZE)
-                    .map(|v| v as u64)
-            })?;
+
+        let packager = header.get_packager().ok().map(|v| v.to_owned());
 
         let provides: Vec<Dependency> = header
             .get_provides()?
             .into_iter()
-            .map(|i| Dependency {
-                dependency: i.name,
-                version: i.version,
-                condition: flag_to_condition(i.flags),
-            })
+            .map(Dependency::new)
             .collect();
 
         let requires: Vec<Dependency> = header
@@ -118,13 +222,18 @@ impl RPMPackage {
             .into_iter()
             .filter(|i| !i.flags.contains(DependencyFlags::RPMLIB))
             .filter(|i| !i.flags.contains(DependencyFlags::CONFIG))
-            .map(|i| Dependency {
-                dependency: i.name,
-                version: i.version,
-                condition: flag_to_condition(i.flags),
-            })
+            .map(Dependency::new)
             .collect();
 
+        let dependencies =
+            |deps: Vec<rpm::Dependency>| deps.into_iter().map(Dependency::new).collect();
+        let conflicts = dependencies(header.get_conflicts()?);
+        let obsoletes = dependencies(header.get_obsoletes()?);
+        let recommends = dependencies(header.get_recommends()?);
+        let suggests = dependencies(header.get_suggests()?);
+        let supplements = dependencies(header.get_supplements()?);
+        let enhances = dependencies(header.get_enhances()?);
+
         let files: Vec<File> = header
             .get_file_entries()?
             .into_iter()
@@ -134,6 +243,8 @@ impl RPMPackage {
             })
             .collect();
 
+        let changelogs = changelogs(header.get_changelog_entries()?);
+
         let location = format!("package/{}/{}", package.version(), package.file_name());
         let pkg_time = package.creation_date().timestamp();
 
@@ -152,17 +263,26 @@ impl RPMPackage {
             build_time,
             build_host,
             source,
+            packager,
             provides,
             requires,
+            conflicts,
+            obsoletes,
+            recommends,
+            suggests,
+            supplements,
+            enhances,
             sha256,
             header_start,
             header_end,
             files,
+            changelogs,
             pkg_size,
             installed_size,
             archive_size,
             location,
             pkg_time,
+            signature,
         };
 
         // Set the matadata to the package
@@ -173,6 +293,139 @@ impl RPMPackage {
     }
 }
 
+impl RPMPackage {
+    /// File name of the package.
+    pub fn file_name(&self) -> &str {
+        self.location.split('/').next_back().unwrap()
+    }
+
+    /// The most recent changelog entries, which are published in the metadata.
+    pub fn recent_changelogs(&self) -> &[Changelog] {
+        recent_changelogs(&self.changelogs, changelog_limit())
+    }
+}
+
+/// Replace the header signature of the package `data` with the recorded `signature`.
+///
+/// The recorded signature is the one made when the package was indexed, so the
+/// served package matches the checksum published in the metadata.
+pub fn resign_package(mut data: &[u8], signature: &[u8]) -> Result<Vec<u8>> {
+    let mut rpm = rpm::Package::parse(&mut data)
+        .context("Unable to parse the package using rpm parser crate")?;
+    sign_package(&mut rpm, &|_| Ok(signature.to_vec()))?;
+
+    let mut signed = Vec::new();
+    rpm.write(&mut signed)?;
+
+    Ok(signed)
+}
+
+/// Replace the signature header of the package with a signature of its header made by `sign`.
+fn sign_package(rpm: &mut rpm::Package, sign: &SignHeader<'_>) -> Result<Vec<u8>> {
+    let signer = HeaderSigner {
+        sign,
+        signature: RefCell::new(None),
+    };
+    rpm.sign_with_timestamp(&signer, 0)?;
+
+    signer
+        .signature
+        .into_inner()
+        .context("The package header wasn't signed")
+}
+
+/// Adapter of a signing function to the signer of the `rpm` crate.
+///
+/// It keeps the signature so that it can be stored and reused.
+struct HeaderSigner<'a> {
+    sign: &'a SignHeader<'a>,
+    signature: RefCell<Option<Vec<u8>>>,
+}
+
+impl Debug for HeaderSigner<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HeaderSigner").finish_non_exhaustive()
+    }
+}
+
+impl Signing for HeaderSigner<'_> {
+    type Signature = Vec<u8>;
+
+    fn sign(&self, mut data: impl io::Read, _t: Timestamp) -> Result<Vec<u8>, rpm::Error> {
+        let mut header = Vec::new();
+        data.read_to_end(&mut header)?;
+
+        let signature = (self.sign)(&header).map_err(io::Error::other)?;
+        *self.signature.borrow_mut() = Some(signature.clone());
+
+        Ok(signature)
+    }
+
+    /// Algorithm of the key which made the signature. It is only called after signing.
+    fn algorithm(&self) -> AlgorithmType {
+        let algorithm = self.signature.borrow().as_deref().and_then(|signature| {
+            match Packet::from_bytes(signature) {
+                Ok(Packet::Signature(signature)) => Some(signature.pk_algo()),
+                _ => None,
+            }
+        });
+
+        match algorithm {
+            Some(PublicKeyAlgorithm::ECDSA) => AlgorithmType::ECDSA,
+            Some(PublicKeyAlgorithm::RSAEncryptSign) => AlgorithmType::RSA,
+            _ => AlgorithmType::EdDSA,
+        }
+    }
+}
+
+/// Whether to re-sign the packages with the PackHub key, so that clients can enable `gpgcheck`.
+///
+/// It can be enabled with the `PACKHUB_RPM_SIGN` variable.
+pub fn sign_packages() -> bool {
+    dotenvy::var("PACKHUB_RPM_SIGN").is_ok_and(|v| v == "true")
+}
+
+/// Maximum number of changelog entries published for a package.
+///
+/// It can be configured with the `PACKHUB_RPM_CHANGELOG_LIMIT` variable.
+fn changelog_limit() -> usize {
+    dotenvy::var("PACKHUB_RPM_CHANGELOG_LIMIT")
+        .ok()
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(DEFAULT_CHANGELOG_LIMIT)
+}
+
+/// Convert the changelog entries into chronological order.
+///
+/// RPM stores the changelog entries from the newest to the oldest.
+fn changelogs(entries: Vec<ChangelogEntry>) -> Vec<Changelog> {
+    let mut changelogs: Vec<Changelog> = entries
+        .into_iter()
+        .map(|entry| Changelog {
+            author: entry.name,
+            date: entry.timestamp,
+            text: entry.description,
+        })
+        .collect();
+    changelogs.reverse();
+
+    changelogs
+}
+
+/// Take the `limit` most recent of the chronologically ordered changelog entries.
+fn recent_changelogs(changelogs: &[Changelog], limit: usize) -> &[Changelog] {
+    &changelogs[changelogs.len().saturating_sub(limit)..]
+}
+
+/// Some packaging tools use the Debian names of the architectures in RPM packages,
+/// which RPM based distributions don't recognize. So use the RPM names for them.
+fn normalize_arch(arch: &str) -> String {
+    match arch.parse::<Arch>() {
+        Ok(canonical) if canonical.dpkg_name() == arch => canonical.rpm_name().to_owned(),
+        _ => arch.to_owned(),
+    }
+}
+
 fn flag_to_condition(flags: DependencyFlags) -> String {
     if flags.contains(DependencyFlags::GE) {
         "GE".to_owned()
@@ -180,6 +433,10 @@ fn flag_to_condition(flags: DependencyFlags) -> String {
         "LE".to_owned()
     } else if flags.contains(DependencyFlags::EQUAL) {
         "EQ".to_owned()
+    } else if flags.contains(DependencyFlags::GREATER) {
+        "GT".to_owned()
+    } else if flags.contains(DependencyFlags::LESS) {
+        "LT".to_owned()
     } else {
         "".to_owned()
     }
@@ -191,8 +448,10 @@ mod tests {
 
     use insta::assert_debug_snapshot;
 
-    use crate::package::tests::package_with_ver;
+    use sequoia_openpgp::{cert::CertBuilder, crypto::Password, policy::StandardPolicy};
+
     use super::*;
+    use crate::{package::tests::package_with_ver, pgp::detached_sign_binary};
 
     #[test]
     fn test_parser() {
@@ -209,6 +468,147 @@ mod tests {
         assert_debug_snapshot!(parsed);
     }
 
+    #[test]
+    fn test_package_signing() {
+        let passphrase: Password = "passphrase".into();
+        let (cert, _) = CertBuilder::new()
+            .set_password(Some(passphrase.clone()))
+            .add_signing_subkey()
+            .generate()
+            .unwrap();
+        let sign = |header: &[u8]| detached_sign_binary(header, &cert, &passphrase);
+
+        let package = package_with_ver("fastfetch-linux-amd64.rpm", "2.40.3");
+        let data = read("data/fastfetch-linux-amd64.rpm").unwrap();
+        package.set_package_data(data.clone());
+        let parsed = RPMPackage::from_package_signed(&package, &sign).unwrap();
+
+        // The proxied package must match the metadata
+        let signed = resign_package(&data, parsed.signature.as_ref().unwrap()).unwrap();
+        assert_eq!(parsed.sha256, hashsum::<Sha256>(&signed));
+        assert_eq!(parsed.pkg_size, signed.len());
+
+        let rpm = rpm::Package::parse(&mut signed.as_slice()).unwrap();
+        let offsets = rpm.metadata.get_package_segment_offsets();
+        assert_eq!(parsed.header_start, offsets.header);
+        assert_eq!(parsed.header_end, offsets.payload);
+
+        // The signature covers the header published in the metadata
+        let signature = rpm
+            .metadata
+            .signature
+            .get_entry_data_as_binary(IndexSignatureTag::RPMSIGTAG_DSA)
+            .unwrap();
+        let Packet::Signature(signature) = Packet::from_bytes(signature).unwrap() else {
+            panic!("The header signature isn't an OpenPGP signature");
+        };
+        let policy = StandardPolicy::new();
+        let key = cert
+            .keys()
+            .with_policy(&policy, None)
+            .for_signing()
+            .next()
+            .unwrap();
+        let header = &signed[parsed.header_start as usize..parsed.header_end as usize];
+        signature.verify_message(key.key(), header).unwrap();
+    }
+
+    #[test]
+    fn test_signing_over_metadata() {
+        let passphrase: Password = "passphrase".into();
+        let (cert, _) = CertBuilder::new()
+            .set_password(Some(passphrase.clone()))
+            .add_signing_subkey()
+            .generate()
+            .unwrap();
+        let sign = |header: &[u8]| detached_sign_binary(header, &cert, &passphrase);
+
+        let package = package_with_ver("fastfetch-linux-amd64.rpm", "2.40.3");
+        package.set_package_data(read("data/fastfetch-linux-amd64.rpm").unwrap());
+        RPMPackage::from_package(&package).unwrap();
+        assert!(RPMPackage::from_package_signed(&package, &sign).is_err());
+
+        let package = package_with_ver("fastfetch-linux-amd64.rpm", "2.40.3");
+        package.set_package_data(read("data/fastfetch-linux-amd64.rpm").unwrap());
+        RPMPackage::from_package_signed(&package, &sign).unwrap();
+        assert!(RPMPackage::from_package(&package).is_err());
+        assert!(
+            RPMPackage::from_package_signed(&package, &sign)
+                .unwrap()
+                .signature
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_normalize_arch() {
+        assert_eq!(normalize_arch("amd64"), "x86_64");
+        assert_eq!(normalize_arch("arm64"), "aarch64");
+        assert_eq!(normalize_arch("all"), "noarch");
+        assert_eq!(normalize_arch("x86_64"), "x86_64");
+        assert_eq!(normalize_arch("armv7l"), "armv7l");
+        assert_eq!(normalize_arch("s390x"), "s390x");
+    }
+
+    #[test]
+    fn test_dependency_version() {
+        let dependency = |version: &str| Dependency {
+            dependency: "openbangla-keyboard".to_owned(),
+            version: version.to_owned(),
+            condition: "LT".to_owned(),
+        };
+
+        let dep = dependency("1:2.0.0-3.fc38");
+        assert_eq!(
+            (dep.epoch(), dep.ver(), dep.rel()),
+            ("1", "2.0.0", Some("3.fc38"))
+        );
+
+        let dep = dependency("2.0.0-3");
+        assert_eq!(
+            (dep.epoch(), dep.ver(), dep.rel()),
+            ("0", "2.0.0", Some("3"))
+        );
+
+        let dep = dependency("2.0.0");
+        assert_eq!((dep.epoch(), dep.ver(), dep.rel()), ("0", "2.0.0", None));
+    }
+
+    #[test]
+    fn test_recent_changelogs() {
+        let entry = |timestamp: u64| ChangelogEntry {
+            name: "Packager <packager@example.com> - 2.0.0-1".to_owned(),
+            timestamp,
+            description: format!("- Release {timestamp}"),
+        };
+        let entries = vec![entry(3), entry(2), entry(1)];
+
+        let changelogs = changelogs(entries);
+        let dates: Vec<u64> = changelogs.iter().map(|c| c.date).collect();
+        assert_eq!(dates, [1, 2, 3]);
+
+        let recent = recent_changelogs(&changelogs, 10);
+        let dates: Vec<u64> = recent.iter().map(|c| c.date).collect();
+        assert_eq!(dates, [1, 2, 3]);
+
+        let recent = recent_changelogs(&changelogs, 2);
+        let dates: Vec<u64> = recent.iter().map(|c| c.date).collect();
+        assert_eq!(dates, [2, 3]);
+        assert_eq!(recent[1].text, "- Release 3");
+
+        assert!(recent_changelogs(&changelogs, 0).is_empty());
+    }
+
+    #[test]
+    fn test_flag_to_condition() {
+        assert_eq!(flag_to_condition(DependencyFlags::GE), "GE");
+        assert_eq!(flag_to_condition(DependencyFlags::LE), "LE");
+        assert_eq!(flag_to_condition(DependencyFlags::EQUAL), "EQ");
+        assert_eq!(flag_to_condition(DependencyFlags::GREATER), "GT");
+        assert_eq!(flag_to_condition(DependencyFlags::LESS), "LT");
+        assert_eq!(flag_to_condition(DependencyFlags::ANY), "");
+    }
+
     #[test]
     #[should_panic]
     fn test_package_without_data() {
@@ -1,73 +1,340 @@
 use anyhow::{Context, anyhow};
 use axum::{
     Router,
-    body::Body,
-    extract::{Path, State},
-    response::IntoResponse,
+    extract::{Path, Request, State},
+    http::HeaderMap,
+    response::Response,
     routing::get,
 };
 use axum_extra::{headers::UserAgent, typed_header::TypedHeader};
-use zstd::encode_all;
+use serde_json::from_str;
 
 use crate::{
-    REQWEST,
+    conditional::Index,
+    db::{PackageMetadata, ReleaseMetadata},
     error::AppError,
+    ident::{AssetPath, Owner, Repo, Tag, project},
+    package::Verification,
+    platform::{detect_rpm_arch, detect_rpm_compression},
+    proxy::{self, Asset, Expected},
     repository::Repository,
-    rpm::{index::get_repomd_index, package::RPMPackage},
+    rpm::{
+        index::get_repomd_index,
+        package::{RPMPackage, resign_package, sign_packages},
+    },
+    selector::{PackageArch, select_for_architecture},
     state::AppState,
+    utils::{Arch, Compression},
 };
 
-use super::index::{get_filelists_index, get_other_index, get_primary_index};
+use super::index::{
+    Advisory, get_filelists_database, get_filelists_index, get_other_database, get_other_index,
+    get_primary_database, get_primary_index, get_updateinfo_index,
+};
 
 #[tracing::instrument(name = "RPM Index", skip_all, fields(agent = agent.as_str()))]
 async fn index(
     State(state): State<AppState>,
-    Path((owner, repo, file)): Path<(String, String, String)>,
+    Path((owner, repo, file)): Path<(Owner, Repo, String)>,
     TypedHeader(agent): TypedHeader<UserAgent>,
-) -> Result<Vec<u8>, AppError> {
-    let mut repo = Repository::from_github(owner, repo, &state).await;
-    let packages: Vec<RPMPackage> = repo
-        .select_package_rpm(agent.as_str())
-        .await?
-        .into_iter()
-        .map(|p| RPMPackage::from_package(&p).context(format!("Error while parsing package into RPMPackage: {p:?}")))
-        .collect::<Result<Vec<_>, _>>()?;
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let arch = detect_rpm_arch(agent.as_str());
+    rpm_index(state, owner, repo, file, agent.as_str(), arch, headers).await
+}
+
+/// Index route for the repositories configured with the `$basearch` variable.
+#[tracing::instrument(name = "RPM Index", skip_all, fields(agent = agent.as_str()))]
+async fn index_with_arch(
+    State(state): State<AppState>,
+    Path((owner, repo, basearch, file)): Path<(Owner, Repo, String, String)>,
+    TypedHeader(agent): TypedHeader<UserAgent>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let arch = basearch
+        .parse()
+        .ok()
+        .or_else(|| detect_rpm_arch(agent.as_str()));
+    rpm_index(state, owner, repo, file, agent.as_str(), arch, headers).await
+}
+
+async fn rpm_index(
+    state: AppState,
+    owner: Owner,
+    repo: Repo,
+    file: String,
+    agent: &str,
+    arch: Option<Arch>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let repository = project(&owner, &repo);
+    let project = format!("{owner}-{repo}");
+    let release = Repository::latest_release(&state, &owner, &repo).await?;
+    let keys = state
+        .released_repository_keys(&owner, &repo, &release)
+        .await?;
+    let signed_by = sign_packages().then(|| keys.fingerprint());
+    let mut repo = Repository::from_release(owner, repo, &state, release, signed_by).await;
+
+    let arch_name = arch.map_or("", |arch| arch.rpm_name());
+    let sqlite = databases().to_string();
+    let tag = repo.client_tag(&[agent, arch_name, &file, &keys.fingerprints(), &sqlite]);
+    if let Some(response) = tag.not_modified(&headers) {
+        return Ok(response);
+    }
+
+    let selected = repo.select_package_rpm(agent, arch).await?;
+    let mut packages: Vec<RPMPackage> = keys
+        .blocking(move |keys| {
+            selected
+                .into_iter()
+                .map(|p| {
+                    let sign = |header: &[u8]| keys.detached_sign_binary(header);
+                    let package = if sign_packages() {
+                        RPMPackage::from_package_signed(&p, &sign)
+                    } else {
+                        RPMPackage::from_package(&p)
+                    };
+                    package.context(format!(
+                        "Error while parsing package into RPMPackage: {p:?}"
+                    ))
+                })
+                .collect()
+        })
+        .await?;
+
+    // The architectures missing from the file names are known after parsing the packages
+    if let Some(arch) = arch {
+        packages = select_for_architecture(
+            packages,
+            arch,
+            |package| package.name.clone(),
+            |package| PackageArch::parse(&package.arch),
+        );
+    }
 
     repo.save_package_metadata().await;
 
-    match file.as_str() {
-        "repomd.xml" => Ok(get_repomd_index(&packages).into_bytes()),
+    // Only the repomd.xml and updateinfo.xml files list the advisories
+    let lists_advisories = matches!(file.as_str(), "repomd.xml" | "repomd.xml.asc")
+        || Compression::split(&file).is_some_and(|(name, _)| name == "updateinfo.xml");
+    let release = repo.release();
+    let previous = if lists_advisories {
+        previous_releases(&state, &repository, release.tag(), &packages).await?
+    } else {
+        Vec::new()
+    };
+    let advisories: Vec<Advisory> = [(release, packages.iter().collect())]
+        .into_iter()
+        .filter(|_| lists_advisories)
+        .chain(
+            previous
+                .iter()
+                .map(|(release, packages)| (release, packages.iter().collect())),
+        )
+        .map(|(release, packages)| Advisory {
+            id: format!("{project}-{}", release.tag()),
+            title: release.title(),
+            kind: release.advisory_type(),
+            description: release.notes().unwrap_or_default(),
+            issued: release.published_at().copied().unwrap_or_default(),
+            packages,
+        })
+        .collect();
+
+    let compression = detect_rpm_compression(agent);
+    let modified = repo.last_modified();
+
+    let (name, compression) = match file.as_str() {
+        "repomd.xml" => {
+            let metadata = get_repomd_index(&packages, &advisories, compression, databases())?;
+            let index = Index::new(metadata.into_bytes(), modified).tagged(&tag);
+            return Ok(index.respond(&headers));
+        }
         "repomd.xml.asc" => {
-            let metadata = get_repomd_index(&packages);
-            let signature = state.detached_sign_metadata(&metadata)?;
-            Ok(signature)
+            let metadata = get_repomd_index(&packages, &advisories, compression, databases())?;
+            let signed = metadata.clone();
+            let signature = keys
+                .blocking(move |keys| keys.detached_sign_metadata(&signed))
+                .await?;
+            let index = Index::signature(signature, metadata.as_bytes(), modified).tagged(&tag);
+            return Ok(index.respond(&headers));
+        }
+        "repomd.xml.key" => {
+            let index = Index::new(keys.armored_public_key(), modified).tagged(&tag);
+            return Ok(index.respond(&headers));
+        }
+        file => Compression::split(file).context(format!("Unknown file requested: {file}"))?,
+    };
+
+    let data = match name {
+        "primary.xml" => get_primary_index(&packages).into_bytes(),
+        "filelists.xml" => get_filelists_index(&packages).into_bytes(),
+        "other.xml" => get_other_index(&packages).into_bytes(),
+        "updateinfo.xml" => get_updateinfo_index(&advisories).into_bytes(),
+        "primary.sqlite" if databases() => get_primary_database(&packages, compression)?,
+        "filelists.sqlite" if databases() => get_filelists_database(&packages, compression)?,
+        "other.sqlite" if databases() => get_other_database(&packages, compression)?,
+        _ => return Err(anyhow!("Unknown file requested: {file}").into()),
+    };
+
+    let index = Index::new(compression.compress(&data), modified).tagged(&tag);
+    Ok(index.respond(&headers))
+}
+
+/// Stored releases other than the `latest` one, with their packages which are still
+/// published in the index under the same name and architecture.
+///
+/// Each of them is published as an advisory, so that the clients can tell which advisories
+/// apply to the installed versions.
+async fn previous_releases(
+    state: &AppState,
+    repository: &str,
+    latest: &str,
+    packages: &[RPMPackage],
+) -> anyhow::Result<Vec<(ReleaseMetadata, Vec<RPMPackage>)>> {
+    let releases = state
+        .db()
+        .database("packhub")
+        .collection::<ReleaseMetadata>("releases");
+    let collection = state
+        .db()
+        .database("github")
+        .collection::<PackageMetadata>(repository);
+
+    let releases: Vec<ReleaseMetadata> = ReleaseMetadata::retrieve_all(&releases, repository)
+        .await?
+        .into_iter()
+        .filter(|release| release.tag() != latest)
+        .collect();
+    let tags: Vec<&str> = releases.iter().map(ReleaseMetadata::tag).collect();
+
+    let mut stored: Vec<(String, RPMPackage)> = Vec::new();
+    for metadata in PackageMetadata::retrieve_released(&collection, &tags).await? {
+        let Ok(package) = from_str::<RPMPackage>(&metadata.metadata) else {
+            continue;
+        };
+        // The metadata of a re-uploaded or re-signed asset is kept along with the previous one
+        let indexed = packages
+            .iter()
+            .any(|p| p.name == package.name && p.arch == package.arch);
+        if indexed && !stored.iter().any(|(_, p)| p.location == package.location) {
+            stored.push((metadata.tag, package));
         }
-        "repomd.xml.key" => Ok(state.armored_public_key()),
-        "primary.xml.zst" => Ok(encode_all(get_primary_index(&packages).as_bytes(), 0)?),
-        "filelists.xml.zst" => Ok(encode_all(get_filelists_index(&packages).as_bytes(), 0)?),
-        "other.xml.zst" => Ok(encode_all(get_other_index(&packages).as_bytes(), 0)?),
-        file => Err(anyhow!("Unknown file requested: {file}").into()),
     }
+
+    let previous = releases
+        .into_iter()
+        .filter_map(|release| {
+            let packages: Vec<RPMPackage> = stored
+                .iter()
+                .filter(|(tag, _)| tag == release.tag())
+                .map(|(_, package)| package.clone())
+                .collect();
+            (!packages.is_empty()).then_some((release, packages))
+        })
+        .collect();
+
+    Ok(previous)
+}
+
+/// Whether to publish the SQLite databases of the metadata.
+///
+/// It can be enabled with the `PACKHUB_RPM_SQLITE` variable.
+fn databases() -> bool {
+    dotenvy::var("PACKHUB_RPM_SQLITE").is_ok_and(|v| v == "true")
 }
 
 #[tracing::instrument(name = "RPM Package proxy", skip_all)]
 async fn package(
-    Path((owner, repo, ver, file)): Path<(String, String, String, String)>,
-) -> Result<impl IntoResponse, AppError> {
-    let url = format!("https://github.com/{owner}/{repo}/releases/download/{ver}/{file}");
-    let res = REQWEST
-        .get(url)
-        .send()
-        .await
-        .context("Error occurred while proxying package")?;
-    let stream = res.bytes_stream();
-    let stream = Body::from_stream(stream);
+    State(state): State<AppState>,
+    Path((owner, repo, asset)): Path<(Owner, Repo, AssetPath)>,
+    request: Request,
+) -> Result<Response, AppError> {
+    proxy_package(state, owner, repo, asset, request).await
+}
+
+#[tracing::instrument(name = "RPM Package proxy", skip_all)]
+async fn package_with_arch(
+    State(state): State<AppState>,
+    Path((owner, repo, _, asset)): Path<(Owner, Repo, String, AssetPath)>,
+    request: Request,
+) -> Result<Response, AppError> {
+    proxy_package(state, owner, repo, asset, request).await
+}
+
+async fn proxy_package(
+    state: AppState,
+    owner: Owner,
+    repo: Repo,
+    asset: AssetPath,
+    request: Request,
+) -> Result<Response, AppError> {
+    let AssetPath { tag: ver, file } = asset;
+    let keys = state.repository_keys(&owner, &repo).await?;
+    let signed_by = sign_packages().then(|| keys.fingerprint());
+    let (package, verification) =
+        served_package(&state, &owner, &repo, &ver, &file, signed_by.as_deref())
+            .await
+            .ok_or_else(|| anyhow!("Package isn't served by the repository: {ver}/{file}"))?;
+    let asset = Asset {
+        owner: &owner,
+        repo: &repo,
+        ver: &ver,
+        file: &file,
+    };
+    let expected = Expected {
+        size: package.pkg_size,
+        sha256: &package.sha256,
+    };
 
-    Ok(stream)
+    // Re-signed packages must be served with the same signature as in the metadata
+    let sign = package
+        .signature
+        .as_ref()
+        .map(|signature| |data: &[u8]| resign_package(data, signature));
+
+    let response = proxy::serve_package(request, asset, expected, sign).await?;
+    Ok(proxy::with_verification(response, verification))
+}
+
+/// Package served in the metadata as `package/{ver}/{file}`, re-signed by the key with
+/// the `signed_by` fingerprint if any.
+async fn served_package(
+    state: &AppState,
+    owner: &Owner,
+    repo: &Repo,
+    ver: &Tag,
+    file: &str,
+    signed_by: Option<&str>,
+) -> Option<(RPMPackage, Verification)> {
+    let collection = state
+        .db()
+        .database("github")
+        .collection::<PackageMetadata>(&project(owner, repo));
+    let location = format!("package/{ver}/{file}");
+
+    PackageMetadata::retrieve_all(&collection, file, signed_by)
+        .await
+        .into_iter()
+        .filter_map(|metadata| {
+            let verification = metadata.verification();
+            let package = from_str::<RPMPackage>(&metadata.data()).ok()?;
+            Some((package, verification))
+        })
+        .find(|(package, _)| package.location == location)
 }
 
 pub fn rpm_routes() -> Router<AppState> {
     Router::new()
         .route("/github/{owner}/{repo}/repodata/{file}", get(index))
-        .route("/github/{owner}/{repo}/package/{ver}/{file}", get(package))
+        .route("/github/{owner}/{repo}/package/{*path}", get(package))
+        .route(
+            "/github/{owner}/{repo}/{basearch}/repodata/{file}",
+            get(index_with_arch),
+        )
+        .route(
+            "/github/{owner}/{repo}/{basearch}/package/{*path}",
+            get(package_with_arch),
+        )
 }
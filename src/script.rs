@@ -1,8 +1,56 @@
 use anyhow::anyhow;
 use askama::Template;
-use axum::{Router, extract::Path, routing::get};
+use axum::{
+    Router,
+    extract::{Path, State},
+    routing::get,
+};
 
-use crate::{error::AppError, state::AppState};
+use crate::{
+    apt,
+    error::AppError,
+    ident::{Owner, Repo},
+    pgp::repository_keys,
+    repository::Repository,
+    rpm,
+    state::AppState,
+};
+
+mod filters {
+    use std::fmt::Display;
+
+    /// Quote the value as a single shell word.
+    pub fn shell<T: Display>(value: T) -> askama::Result<String> {
+        Ok(format!("'{}'", value.to_string().replace('\'', r"'\''")))
+    }
+}
+
+/// Path of the key signing the repository, relative to `/v1/keys` and without extension.
+fn key_path(owner: &str, repo: &str) -> String {
+    if repository_keys() {
+        format!("github/{owner}/{repo}")
+    } else {
+        "packhub".to_owned()
+    }
+}
+
+/// Path of the `debsig-verify` policy of the repository, relative to `/v1/keys` and without extension.
+fn policy_path(owner: &str, repo: &str) -> String {
+    if repository_keys() {
+        format!("github/{owner}/{repo}/packhub")
+    } else {
+        "packhub".to_owned()
+    }
+}
+
+/// Name of the keyring the key is installed as.
+fn keyring_name(owner: &str, repo: &str) -> String {
+    if repository_keys() {
+        format!("packhub-{owner}-{repo}")
+    } else {
+        "packhub".to_owned()
+    }
+}
 
 #[derive(Template)]
 #[template(path = "apt-script.sh", escape = "none")]
@@ -11,15 +59,27 @@ struct AptScript<'a> {
     distro: &'a str,
     owner: &'a str,
     repo: &'a str,
+    key: &'a str,
+    keyring: &'a str,
+    /// Install the `debsig-verify` policy and keyring of the signed packages.
+    debsig: bool,
+    policy: &'a str,
 }
 
-fn generate_apt_script(distro: &str, owner: &str, repo: &str) -> String {
+fn generate_apt_script(distro: &str, owner: &str, repo: &str, debsig: bool) -> String {
     let host = dotenvy::var("PACKHUB_DOMAIN").unwrap();
+    let key = key_path(owner, repo);
+    let keyring = keyring_name(owner, repo);
+    let policy = policy_path(owner, repo);
     let script = AptScript {
         host: &host,
         distro,
         owner,
         repo,
+        key: &key,
+        keyring: &keyring,
+        debsig,
+        policy: &policy,
     };
     script.render().unwrap()
 }
@@ -31,24 +91,43 @@ struct RPMScript<'a> {
     owner: &'a str,
     repo: &'a str,
     mgr: &'a str,
+    key: &'a str,
+    gpgcheck: u8,
 }
 
 fn generate_rpm_script(owner: &str, repo: &str, mgr: &str) -> String {
     let host = dotenvy::var("PACKHUB_DOMAIN").unwrap();
+    let key = key_path(owner, repo);
     let script = RPMScript {
         host: &host,
         owner,
         repo,
         mgr,
+        key: &key,
+        gpgcheck: rpm::sign_packages().into(),
     };
     script.render().unwrap()
 }
 
 async fn script_handler(
-    Path((distro, owner, repo)): Path<(String, String, String)>,
+    State(state): State<AppState>,
+    Path((distro, owner, repo)): Path<(String, Owner, Repo)>,
 ) -> Result<String, AppError> {
+    // The key of the repository is installed by the script before the indices are requested
+    if repository_keys() {
+        let release = Repository::latest_release(&state, &owner, &repo).await?;
+        state
+            .released_repository_keys(&owner, &repo, &release)
+            .await?;
+    }
+
     match distro.as_str() {
-        "ubuntu" | "debian" => Ok(generate_apt_script(&distro, &owner, &repo)),
+        "ubuntu" | "debian" => Ok(generate_apt_script(
+            &distro,
+            &owner,
+            &repo,
+            apt::sign_packages(),
+        )),
         "yum" => Ok(generate_rpm_script(&owner, &repo, "yum.repos.d")),
         "zypp" => Ok(generate_rpm_script(&owner, &repo, "zypp/repos.d")),
         _ => Err(anyhow!("Script Generation: Unsupported distro: {}", distro).into()),
@@ -67,7 +146,10 @@ mod tests {
 
     #[test]
     fn test_script_generation_apt() {
-        let apt_script = generate_apt_script("ubuntu", "OpenBangla", "OpenBangla-Keyboard");
+        let apt_script = generate_apt_script("ubuntu", "OpenBangla", "OpenBangla-Keyboard", false);
+        assert_snapshot!(apt_script);
+
+        let apt_script = generate_apt_script("debian", "OpenBangla", "OpenBangla-Keyboard", true);
         assert_snapshot!(apt_script);
     }
 
@@ -79,4 +161,10 @@ mod tests {
         let zypp = generate_rpm_script("OpenBangla", "OpenBangla-Keyboard", "zypp/repos.d");
         assert_snapshot!(zypp);
     }
+
+    #[test]
+    fn test_shell_quoting() {
+        assert_eq!(filters::shell("OpenBangla").unwrap(), "'OpenBangla'");
+        assert_eq!(filters::shell("it's $(id)").unwrap(), r"'it'\''s $(id)'");
+    }
 }
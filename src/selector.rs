@@ -3,9 +3,34 @@
 
 use std::collections::HashMap;
 
-use crate::{package::Package, utils::Dist};
+use crate::{
+    package::Package,
+    utils::{Arch, Dist},
+};
+
+/// Select the packages for the distribution `dist`.
+///
+/// When the architecture of the client is known, only the packages
+/// compatible with it are selected.
+pub(crate) fn select_packages(from: &[Package], dist: Dist, arch: Option<Arch>) -> Vec<&Package> {
+    let packages = select_for_distribution(from, dist);
+
+    match arch {
+        Some(arch) => select_for_architecture(
+            packages,
+            arch,
+            |package| package.name().unwrap_or(package.file_name()).to_owned(),
+            |package| {
+                package
+                    .detected_architecture()
+                    .map_or(PackageArch::Missing, PackageArch::Known)
+            },
+        ),
+        None => packages,
+    }
+}
 
-pub(crate) fn select_packages(from: &[Package], dist: Dist) -> Vec<&Package> {
+fn select_for_distribution(from: &[Package], dist: Dist) -> Vec<&Package> {
     let mut packages = Vec::new();
 
     // Filter out the packages that are not for the distribution.
@@ -20,10 +45,10 @@ pub(crate) fn select_packages(from: &[Package], dist: Dist) -> Vec<&Package> {
 
     // Loosely match the distribution (without regarding the distribution version).
     for package in packages.iter() {
-        if let Some(pack_dist) = package.distribution() {
-            if dist.matches_distribution(pack_dist) {
-                selective.push(*package);
-            }
+        if let Some(pack_dist) = package.distribution()
+            && dist.matches_distribution(pack_dist)
+        {
+            selective.push(*package);
         }
     }
 
@@ -77,6 +102,70 @@ pub(crate) fn select_packages(from: &[Package], dist: Dist) -> Vec<&Package> {
     packages
 }
 
+/// Architecture of a package, as far as it is known.
+pub(crate) enum PackageArch {
+    /// The architecture is missing, e.g. from the file name, and is only known after parsing.
+    Missing,
+    Known(Arch),
+    /// The architecture isn't one of the supported ones, so no client is compatible with it.
+    Unrecognized,
+}
+
+impl PackageArch {
+    /// Architecture named `name` in the package.
+    pub(crate) fn parse(name: &str) -> Self {
+        name.parse().map_or(Self::Unrecognized, Self::Known)
+    }
+}
+
+/// Keep the packages compatible with `arch`, preferring the native builds.
+///
+/// The packages are grouped by their `name`. Packages whose `architecture` is missing are kept
+/// as is, like the packages without any architecture in their file names, which can only be
+/// known after parsing them. The packages of unrecognized architectures are left out.
+pub(crate) fn select_for_architecture<T>(
+    from: Vec<T>,
+    arch: Arch,
+    name: impl Fn(&T) -> String,
+    architecture: impl Fn(&T) -> PackageArch,
+) -> Vec<T> {
+    let compatible = arch.compatible();
+
+    // Preference of each package, `None` when its architecture is missing
+    // and `Some(None)` when it is incompatible.
+    let preferences: Vec<(String, Option<Option<usize>>)> = from
+        .iter()
+        .map(|package| {
+            let preference = match architecture(package) {
+                PackageArch::Missing => None,
+                PackageArch::Known(arch) => Some(compatible.iter().position(|a| *a == arch)),
+                PackageArch::Unrecognized => Some(None),
+            };
+            (name(package), preference)
+        })
+        .collect();
+
+    // The most preferred architecture of each group.
+    let mut best: HashMap<&str, usize> = HashMap::new();
+    for (name, preference) in preferences.iter() {
+        if let Some(Some(preference)) = preference {
+            let entry = best.entry(name).or_insert(*preference);
+            *entry = (*entry).min(*preference);
+        }
+    }
+
+    from.into_iter()
+        .zip(preferences.iter())
+        .filter(|(_, (name, preference))| match preference {
+            Some(preference) => {
+                preference.is_some() && best.get(name.as_str()) == preference.as_ref()
+            }
+            None => true,
+        })
+        .map(|(package, _)| package)
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -123,7 +212,7 @@ mod tests {
     // Because `select_packages` sorts the packages by the distribution
     // which can change the order of the packages when multiple packages
     // are present. So we need to sort the packages by their file name.
-    fn sort<'a>(mut v: Vec<&'a Package>) -> Vec<&'a Package> {
+    fn sort(mut v: Vec<&Package>) -> Vec<&Package> {
         v.sort();
         v
     }
@@ -133,15 +222,15 @@ mod tests {
         let packages: Vec<Package> = openbangla_keyboard_packages();
 
         assert_eq!(
-            select_packages(&packages, Dist::ubuntu("18.04")),
+            select_packages(&packages, Dist::ubuntu("18.04"), None),
             vec![&package("OpenBangla-Keyboard_2.0.0-ubuntu18.04.deb")]
         );
         assert_eq!(
-            select_packages(&packages, Dist::ubuntu("20.04")),
+            select_packages(&packages, Dist::ubuntu("20.04"), None),
             vec![&package("OpenBangla-Keyboard_2.0.0-ubuntu20.04.deb")]
         );
         assert_eq!(
-            select_packages(&packages, Dist::ubuntu("22.04")),
+            select_packages(&packages, Dist::ubuntu("22.04"), None),
             vec![&package("OpenBangla-Keyboard_2.0.0-ubuntu22.04.deb")]
         );
     }
@@ -151,7 +240,7 @@ mod tests {
         let packages: Vec<Package> = openbangla_keyboard_packages();
 
         assert_eq!(
-            select_packages(&packages, Dist::fedora("38")),
+            select_packages(&packages, Dist::fedora("38"), None),
             vec![&package("OpenBangla-Keyboard_2.0.0-fedora38.rpm")]
         );
     }
@@ -161,7 +250,7 @@ mod tests {
         let packages: Vec<Package> = openbangla_keyboard_packages();
 
         assert_eq!(
-            select_packages(&packages, Dist::debian("11")),
+            select_packages(&packages, Dist::debian("11"), None),
             vec![&package("OpenBangla-Keyboard_2.0.0-debian11.deb")]
         );
     }
@@ -171,7 +260,7 @@ mod tests {
         let packages = multiple_packages();
 
         assert_eq!(
-            sort(select_packages(&packages, Dist::ubuntu("22.04"))),
+            sort(select_packages(&packages, Dist::ubuntu("22.04"), None)),
             vec![
                 &package("fcitx-openbangla_3.0.0.deb"),
                 &package("ibus-openbangla_3.0.0.deb")
@@ -179,7 +268,7 @@ mod tests {
         );
 
         assert_eq!(
-            sort(select_packages(&packages, Dist::fedora("39"))),
+            sort(select_packages(&packages, Dist::fedora("39"), None)),
             vec![
                 &package("fcitx-openbangla_3.0.0-fedora.rpm"),
                 &package("ibus-openbangla_3.0.0-fedora.rpm")
@@ -187,7 +276,7 @@ mod tests {
         );
 
         assert_eq!(
-            sort(select_packages(&packages, Dist::Tumbleweed)),
+            sort(select_packages(&packages, Dist::Tumbleweed, None)),
             vec![
                 &package("fcitx-openbangla_3.0.0-opensuse-tumbleweed.rpm"),
                 &package("ibus-openbangla_3.0.0-opensuse-tumbleweed.rpm")
@@ -200,11 +289,11 @@ mod tests {
         let packages: Vec<Package> = openbangla_keyboard_packages();
 
         assert_eq!(
-            select_packages(&packages, Dist::fedora("41")),
+            select_packages(&packages, Dist::fedora("41"), None),
             vec![&package("OpenBangla-Keyboard_2.0.0-fedora38.rpm")]
         );
         assert_eq!(
-            select_packages(&packages, Dist::ubuntu("24.04")),
+            select_packages(&packages, Dist::ubuntu("24.04"), None),
             vec![&package("OpenBangla-Keyboard_2.0.0-ubuntu22.04.deb")]
         );
 
@@ -223,22 +312,22 @@ mod tests {
         ];
 
         assert_eq!(
-            select_packages(&packages, Dist::fedora("39")),
+            select_packages(&packages, Dist::fedora("39"), None),
             vec![&package("flameshot-12.1.0-1.fc36.x86_64.rpm")]
         );
 
         assert_eq!(
-            select_packages(&packages, Dist::ubuntu("21.04")),
+            select_packages(&packages, Dist::ubuntu("21.04"), None),
             vec![&package("flameshot-12.1.0-1.ubuntu-20.04.amd64.deb")]
         );
 
         assert_eq!(
-            select_packages(&packages, Dist::ubuntu("24.04")),
+            select_packages(&packages, Dist::ubuntu("24.04"), None),
             vec![&package("flameshot-12.1.0-1.ubuntu-22.04.amd64.deb")]
         );
 
         assert_eq!(
-            sort(select_packages(&packages, Dist::debian("12"))),
+            sort(select_packages(&packages, Dist::debian("12"), None)),
             vec![
                 &package("flameshot-12.1.0-1.debian-11.amd64.deb"),
                 &package("flameshot-12.1.0-1.debian-11.arm64.deb"),
@@ -247,13 +336,120 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_package_selection_architecture() {
+        let packages = [
+            package("flameshot-12.1.0-1.debian-11.amd64.deb"),
+            package("flameshot-12.1.0-1.debian-11.arm64.deb"),
+            package("flameshot-12.1.0-1.debian-11.armhf.deb"),
+            package("flameshot-12.1.0-1.fc36.x86_64.rpm"),
+            package("flameshot-12.1.0-1.fc36.aarch64.rpm"),
+            package("flameshot-12.1.0-1.fc36.i686.rpm"),
+            package("flameshot-data-12.1.0-1.fc36.noarch.rpm"),
+            package("flameshot-docs-12.1.0-1.fc36.rpm"),
+        ];
+
+        assert_eq!(
+            sort(select_packages(
+                &packages,
+                Dist::fedora("39"),
+                Some(Arch::Amd64)
+            )),
+            vec![
+                &package("flameshot-12.1.0-1.fc36.x86_64.rpm"),
+                &package("flameshot-data-12.1.0-1.fc36.noarch.rpm"),
+                &package("flameshot-docs-12.1.0-1.fc36.rpm"),
+            ]
+        );
+
+        assert_eq!(
+            sort(select_packages(
+                &packages,
+                Dist::fedora("39"),
+                Some(Arch::Arm64)
+            )),
+            vec![
+                &package("flameshot-12.1.0-1.fc36.aarch64.rpm"),
+                &package("flameshot-data-12.1.0-1.fc36.noarch.rpm"),
+                &package("flameshot-docs-12.1.0-1.fc36.rpm"),
+            ]
+        );
+
+        assert_eq!(
+            sort(select_packages(
+                &packages,
+                Dist::fedora("39"),
+                Some(Arch::S390x)
+            )),
+            vec![
+                &package("flameshot-data-12.1.0-1.fc36.noarch.rpm"),
+                &package("flameshot-docs-12.1.0-1.fc36.rpm"),
+            ]
+        );
+
+        assert_eq!(
+            select_packages(&packages, Dist::debian("12"), Some(Arch::Armhf)),
+            vec![&package("flameshot-12.1.0-1.debian-11.armhf.deb")]
+        );
+
+        // The 32-bit builds aren't served to the 64-bit clients
+        let packages = [
+            package("flameshot-12.1.0-1.fc36.x86_64.rpm"),
+            package("flameshot-libs-12.1.0-1.fc36.i686.rpm"),
+        ];
+        assert_eq!(
+            select_packages(&packages, Dist::fedora("39"), Some(Arch::Amd64)),
+            vec![&package("flameshot-12.1.0-1.fc36.x86_64.rpm")]
+        );
+    }
+
+    #[test]
+    fn test_architecture_selection_after_parsing() {
+        // Name and architecture of the packages, as read from their headers
+        let packages = vec![
+            ("flameshot", "x86_64"),
+            ("flameshot", "i686"),
+            ("flameshot-data", "noarch"),
+            ("flameshot-libs", "i686"),
+            ("flameshot-docs", "sparc64"),
+        ];
+
+        assert_eq!(
+            select_for_architecture(
+                packages,
+                Arch::Amd64,
+                |(name, _)| name.to_string(),
+                |(_, arch)| PackageArch::parse(arch)
+            ),
+            vec![("flameshot", "x86_64"), ("flameshot-data", "noarch")]
+        );
+    }
+
     #[test]
     fn test_package_selection_without_dist() {
         let packages = [package("caprine_2.60.3_amd64.deb")];
 
         assert_eq!(
-            select_packages(&packages, Dist::ubuntu("24.04")),
+            select_packages(&packages, Dist::ubuntu("24.04"), None),
             vec![&package("caprine_2.60.3_amd64.deb")]
         );
     }
+
+    #[test]
+    fn test_package_selection_noarch() {
+        let packages = [
+            package("flameshot-12.1.0-1.fc36.x86_64.rpm"),
+            package("flameshot-data-12.1.0-1.fc36.noarch.rpm"),
+            package("flameshot-12.1.0-1.ubuntu-22.04.amd64.deb"),
+        ];
+
+        // Architecture independent packages are selected along with the others.
+        assert_eq!(
+            sort(select_packages(&packages, Dist::fedora("39"), None)),
+            vec![
+                &package("flameshot-12.1.0-1.fc36.x86_64.rpm"),
+                &package("flameshot-data-12.1.0-1.fc36.noarch.rpm"),
+            ]
+        );
+    }
 }
@@ -0,0 +1,410 @@
+//! Backends creating the signatures of the repositories.
+//!
+//! The signatures are either made in process with the decrypted keys, or by an external signer
+//! holding the keys, so that the secret keys and their passphrase stay out of the memory of the
+//! public facing server. `packhub keys sign` and `packhub keys serve` implement the external
+//! signer with the keys of PackHub.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use clap::ValueEnum;
+use sequoia_openpgp::crypto::Password;
+use tracing::{error, info};
+
+use crate::pgp::{Keyring, clearsign_metadata, detached_sign_binary, detached_sign_metadata};
+
+/// Time the external signer has to create a signature.
+const SIGNER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Creates the signatures with the signing keys.
+///
+/// Signing blocks the thread, so the async code signs on the blocking threads of the runtime.
+pub trait Signer: Send + Sync {
+    /// Clearsign the metadata with all the active keys.
+    fn clearsign_metadata(&self, data: &str) -> Result<Vec<u8>>;
+
+    /// Detached sign the metadata with all the active keys.
+    fn detached_sign_metadata(&self, data: &str) -> Result<Vec<u8>>;
+
+    /// Detached sign the data with the current key.
+    fn detached_sign_binary(&self, data: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Signer decrypting the keys in process.
+pub struct KeySigner {
+    keyring: Arc<Keyring>,
+    passphrase: Password,
+}
+
+impl KeySigner {
+    pub fn new(keyring: Arc<Keyring>, passphrase: Password) -> Self {
+        Self {
+            keyring,
+            passphrase,
+        }
+    }
+}
+
+impl Signer for KeySigner {
+    fn clearsign_metadata(&self, data: &str) -> Result<Vec<u8>> {
+        clearsign_metadata(data, &self.keyring.active(), &self.passphrase)
+    }
+
+    fn detached_sign_metadata(&self, data: &str) -> Result<Vec<u8>> {
+        detached_sign_metadata(data, &self.keyring.active(), &self.passphrase)
+    }
+
+    fn detached_sign_binary(&self, data: &[u8]) -> Result<Vec<u8>> {
+        detached_sign_binary(data, self.keyring.current(), &self.passphrase)
+    }
+}
+
+/// Signing operations requested from an external signer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Operation {
+    Clearsign,
+    DetachSign,
+    DetachSignBinary,
+}
+
+impl Operation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Clearsign => "clearsign",
+            Operation::DetachSign => "detach-sign",
+            Operation::DetachSignBinary => "detach-sign-binary",
+        }
+    }
+
+    /// Perform the operation on the `data` with the `signer`.
+    pub fn sign(&self, signer: &dyn Signer, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Operation::Clearsign => signer.clearsign_metadata(std::str::from_utf8(data)?),
+            Operation::DetachSign => signer.detached_sign_metadata(std::str::from_utf8(data)?),
+            Operation::DetachSignBinary => signer.detached_sign_binary(data),
+        }
+    }
+}
+
+impl FromStr for Operation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "clearsign" => Ok(Operation::Clearsign),
+            "detach-sign" => Ok(Operation::DetachSign),
+            "detach-sign-binary" => Ok(Operation::DetachSignBinary),
+            _ => Err(anyhow!("Unknown signing operation: {s}")),
+        }
+    }
+}
+
+/// Signer delegating the signatures to another process.
+///
+/// It is configured with the `PACKHUB_SIGNER` variable:
+/// * `command:<program> [args]` runs the program for each signature with the operation appended
+///   to its arguments, which are split like the words of a shell command. The data is written to its standard input and the signature is read from
+///   its standard output.
+/// * `unix:<path>` connects to a signer listening on the Unix socket. The operation is sent on
+///   the first line followed by the data. It replies with `OK` and the signature, or with `ERR`
+///   and the error on the first line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExternalSigner {
+    Command(Vec<String>),
+    Socket(PathBuf),
+}
+
+impl ExternalSigner {
+    /// Read the external signer from the `PACKHUB_SIGNER` variable.
+    ///
+    /// `None` is returned when the keys are used in process.
+    pub fn from_env() -> Result<Option<Self>> {
+        let signer = dotenvy::var("PACKHUB_SIGNER").unwrap_or_default();
+        if signer.is_empty() {
+            return Ok(None);
+        }
+
+        signer.parse().map(Some)
+    }
+
+    /// Request the signature from the external signer, which has [`SIGNER_TIMEOUT`] to reply.
+    fn sign(&self, operation: Operation, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            ExternalSigner::Command(command) => sign_with_command(command, operation, data),
+            ExternalSigner::Socket(path) => sign_with_socket(path, operation, data),
+        }
+    }
+}
+
+impl FromStr for ExternalSigner {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(command) = s.strip_prefix("command:") {
+            let command = shell_words::split(command)
+                .with_context(|| format!("Invalid command of the external signer: {command}"))?;
+            if command.is_empty() {
+                bail!("No command specified for the external signer");
+            }
+            Ok(ExternalSigner::Command(command))
+        } else if let Some(path) = s.strip_prefix("unix:") {
+            Ok(ExternalSigner::Socket(path.into()))
+        } else {
+            Err(anyhow!("Unknown external signer: {s}"))
+        }
+    }
+}
+
+impl Signer for ExternalSigner {
+    fn clearsign_metadata(&self, data: &str) -> Result<Vec<u8>> {
+        self.sign(Operation::Clearsign, data.as_bytes())
+    }
+
+    fn detached_sign_metadata(&self, data: &str) -> Result<Vec<u8>> {
+        self.sign(Operation::DetachSign, data.as_bytes())
+    }
+
+    fn detached_sign_binary(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.sign(Operation::DetachSignBinary, data)
+    }
+}
+
+fn sign_with_command(command: &[String], operation: Operation, data: &[u8]) -> Result<Vec<u8>> {
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .arg(operation.as_str())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run the external signer: {}", command[0]))?;
+
+    // Write from another thread, so that a large output can't block the signer
+    let mut stdin = child
+        .stdin
+        .take()
+        .context("No stdin of the external signer")?;
+    let data = data.to_vec();
+    let writer = thread::spawn(move || stdin.write_all(&data));
+    let stdout = read_in_thread(child.stdout.take());
+    let stderr = read_in_thread(child.stderr.take());
+
+    let deadline = Instant::now() + SIGNER_TIMEOUT;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("External signer timed out: {}", command[0]);
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    let failed = |_| anyhow!("Failed to communicate with the external signer");
+    writer.join().map_err(failed)??;
+    let stdout = stdout.join().map_err(failed)??;
+    let stderr = stderr.join().map_err(failed)??;
+
+    if !status.success() {
+        bail!(
+            "External signer failed with {status}: {}",
+            String::from_utf8_lossy(&stderr).trim()
+        );
+    }
+
+    Ok(stdout)
+}
+
+/// Read the output of the external signer from another thread, so that a large output
+/// can't block it.
+fn read_in_thread(
+    output: Option<impl Read + Send + 'static>,
+) -> thread::JoinHandle<std::io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut data = Vec::new();
+        if let Some(mut output) = output {
+            output.read_to_end(&mut data)?;
+        }
+        Ok(data)
+    })
+}
+
+fn sign_with_socket(path: &Path, operation: Operation, data: &[u8]) -> Result<Vec<u8>> {
+    let mut stream = UnixStream::connect(path).with_context(|| {
+        format!(
+            "Failed to connect to the external signer: {}",
+            path.display()
+        )
+    })?;
+    stream.set_read_timeout(Some(SIGNER_TIMEOUT))?;
+    stream.set_write_timeout(Some(SIGNER_TIMEOUT))?;
+
+    stream.write_all(operation.as_str().as_bytes())?;
+    stream.write_all(b"\n")?;
+    stream.write_all(data)?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+
+    match status.trim_end().split_once(' ') {
+        None if status.trim_end() == "OK" => {
+            let mut signature = Vec::new();
+            reader.read_to_end(&mut signature)?;
+            Ok(signature)
+        }
+        Some(("ERR", e)) => Err(anyhow!("External signer failed: {e}")),
+        _ => Err(anyhow!("Invalid response of the external signer: {status}")),
+    }
+}
+
+/// Serve the signatures of the `signer` on the Unix socket at `path`.
+pub fn serve(signer: Arc<dyn Signer>, path: &Path) -> Result<()> {
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    info!("Signer listening on {}", path.display());
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let signer = signer.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_request(signer.as_ref(), stream) {
+                error!("Signing request failed: {e}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn handle_request(signer: &dyn Signer, stream: UnixStream) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut operation = String::new();
+    reader.read_line(&mut operation)?;
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    let signature = operation
+        .trim_end()
+        .parse::<Operation>()
+        .and_then(|operation| operation.sign(signer, &data));
+
+    let mut stream = &stream;
+    match signature {
+        Ok(signature) => {
+            stream.write_all(b"OK\n")?;
+            stream.write_all(&signature)?;
+        }
+        Err(e) => {
+            // Errors are reported on a single line
+            let e = e.to_string().replace('\n', " ");
+            stream.write_all(format!("ERR {e}\n").as_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pgp::{generate_cert, verify_detached_signature};
+    use sequoia_openpgp::cert::CipherSuite;
+
+    #[test]
+    fn test_external_signer_config() {
+        assert_eq!(
+            "command:packhub keys sign"
+                .parse::<ExternalSigner>()
+                .unwrap(),
+            ExternalSigner::Command(vec!["packhub".into(), "keys".into(), "sign".into()])
+        );
+        assert_eq!(
+            "unix:/run/packhub/signer.sock"
+                .parse::<ExternalSigner>()
+                .unwrap(),
+            ExternalSigner::Socket("/run/packhub/signer.sock".into())
+        );
+        assert_eq!(
+            r#"command:"/opt/Sign Tool/sign" --key 'repo key'"#.parse::<ExternalSigner>().unwrap(),
+            ExternalSigner::Command(vec![
+                "/opt/Sign Tool/sign".into(),
+                "--key".into(),
+                "repo key".into()
+            ])
+        );
+        assert!("command:".parse::<ExternalSigner>().is_err());
+        assert!("command:sign 'key".parse::<ExternalSigner>().is_err());
+        assert!(
+            "/run/packhub/signer.sock"
+                .parse::<ExternalSigner>()
+                .is_err()
+        );
+    }
+
+    // The signers can be used within a runtime of a single thread
+    #[tokio::test]
+    async fn test_command_signer() {
+        let command =
+            |script: &str| ExternalSigner::Command(vec!["sh".into(), "-c".into(), script.into()]);
+
+        // The operation is passed as `$0` of the script
+        let signer = command(r#"printf "%s:" "$0"; cat"#);
+        assert_eq!(
+            signer.detached_sign_binary(b"data").unwrap(),
+            b"detach-sign-binary:data"
+        );
+
+        let signer = command("echo 'no key' >&2; exit 1");
+        let error = signer.clearsign_metadata("data").unwrap_err();
+        assert!(error.to_string().ends_with("no key"));
+    }
+
+    #[test]
+    fn test_socket_signer() -> Result<()> {
+        let passphrase: Password = "passphrase".into();
+        let cert = generate_cert(&passphrase, CipherSuite::Cv25519)?;
+        let keyring = Arc::new(Keyring::new(cert.clone(), Vec::new(), None));
+        let signer = Arc::new(KeySigner::new(keyring, passphrase));
+
+        let dir = std::env::temp_dir().join(format!("packhub-signer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("signer.sock");
+        let _ = std::fs::remove_file(&path);
+
+        let listener_path = path.clone();
+        thread::spawn(move || serve(signer, &listener_path));
+        while !path.exists() {
+            thread::yield_now();
+        }
+
+        let external = ExternalSigner::Socket(path);
+        let data = b"Test data to be signed";
+        let signature = external.detached_sign_binary(data)?;
+        verify_detached_signature(data, &signature, &cert)?;
+
+        let signature = external.detached_sign_metadata("Test metadata")?;
+        verify_detached_signature(b"Test metadata", &signature, &cert)?;
+
+        // Errors of the signer are reported to the client
+        let error = external.sign(Operation::DetachSign, &[0xff]).unwrap_err();
+        assert!(error.to_string().starts_with("External signer failed"));
+
+        std::fs::remove_dir_all(dir)?;
+
+        Ok(())
+    }
+}
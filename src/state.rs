@@ -1,13 +1,19 @@
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
 use dotenvy::var;
 use mongodb::Client;
-use octocrab::{Octocrab, OctocrabBuilder};
-use sequoia_openpgp::{Cert, crypto::Password, serialize::SerializeInto};
+use octocrab::{Octocrab, OctocrabBuilder, models::repos::Release};
+use sequoia_openpgp::{Cert, crypto::Password};
 
-use crate::pgp::{
-    clearsign_metadata, detached_sign_metadata, generate_and_save_keys, load_cert_from_file,
+use crate::{
+    db::RepositoryKey,
+    ident::{Owner, Repo, project},
+    pgp::{
+        Keyring, generate_and_save_keys, generate_repository_cert, load_cert_from_file,
+        public_keyring, repository_keys, signing_key_id,
+    },
+    signer::{ExternalSigner, KeySigner, Signer},
 };
 
 #[derive(Clone)]
@@ -17,9 +23,10 @@ pub struct AppState {
 
 struct InnerState {
     db: Client,
-    cert: Cert,
+    keyring: Arc<Keyring>,
+    signer: Arc<dyn Signer>,
     github: Octocrab,
-    passphrase: Password,
+    passphrase: Option<Password>,
 }
 
 impl AppState {
@@ -32,17 +39,39 @@ impl AppState {
         );
 
         let client = Client::with_uri_str(uri).await.unwrap();
-        let passphrase = var("PACKHUB_SIGN_PASSPHRASE").unwrap().into();
 
+        // The passphrase isn't needed when the keys are held by an external signer
+        let external = ExternalSigner::from_env().unwrap();
+        assert!(
+            external.is_none() || !repository_keys(),
+            "PACKHUB_REPOSITORY_KEYS can't be enabled with an external signer (PACKHUB_SIGNER)"
+        );
+        let passphrase: Option<Password> = match external {
+            Some(_) => None,
+            None => Some(var("PACKHUB_SIGN_PASSPHRASE").unwrap().into()),
+        };
+
+        // Only the public keys are needed when they are held by an external signer
         let cert = if generate_keys {
-            generate_and_save_keys(&passphrase).unwrap()
+            let passphrase = passphrase
+                .as_ref()
+                .expect("Keys can't be generated for an external signer");
+            generate_and_save_keys(passphrase).unwrap()
+        } else if external.is_some() {
+            load_cert_from_file().unwrap().strip_secret_key_material()
         } else {
             load_cert_from_file().unwrap()
         };
+        let keyring = Arc::new(Keyring::from_env(cert).unwrap());
+
+        let signer: Arc<dyn Signer> = match external {
+            Some(external) => Arc::new(external),
+            None => Arc::new(KeySigner::new(keyring.clone(), passphrase.clone().unwrap())),
+        };
 
         let pat = var("PACKHUB_GITHUB_PAT").unwrap();
 
-        let github = if pat != "" {
+        let github = if !pat.is_empty() {
             OctocrabBuilder::default()
                 .personal_token(var("PACKHUB_GITHUB_PAT").unwrap())
                 .build()
@@ -54,7 +83,8 @@ impl AppState {
         Self {
             state: Arc::new(InnerState {
                 db: client,
-                cert,
+                keyring,
+                signer,
                 github,
                 passphrase,
             }),
@@ -71,19 +101,169 @@ impl AppState {
         &self.state.github
     }
 
+    /// Signing keys of the instance.
+    pub fn keys(&self) -> SigningKeys {
+        SigningKeys {
+            keyring: self.state.keyring.clone(),
+            signer: self.state.signer.clone(),
+        }
+    }
+
+    /// Signing keys of the `owner/repo` repository.
+    ///
+    /// The keys of the instance are used unless the per-repository keys are enabled, in which
+    /// case the key stored for the repository is used. They are always used in process,
+    /// encrypted with the passphrase of the instance.
+    pub async fn repository_keys(&self, owner: &Owner, repo: &Repo) -> Result<SigningKeys> {
+        self.keys_of(owner, repo, None).await
+    }
+
+    /// Signing keys of the `owner/repo` repository, whose latest `release` was found on GitHub.
+    ///
+    /// The key of the repository is generated on its first use, so that the keys are only
+    /// generated for the repositories with a release which are requested by the clients.
+    pub async fn released_repository_keys(
+        &self,
+        owner: &Owner,
+        repo: &Repo,
+        release: &Release,
+    ) -> Result<SigningKeys> {
+        self.keys_of(owner, repo, Some(release)).await
+    }
+
+    async fn keys_of(
+        &self,
+        owner: &Owner,
+        repo: &Repo,
+        release: Option<&Release>,
+    ) -> Result<SigningKeys> {
+        if !repository_keys() {
+            return Ok(self.keys());
+        }
+
+        let passphrase = self
+            .state
+            .passphrase
+            .clone()
+            .context("Per-repository keys can't be used with an external signer")?;
+
+        let repository = project(owner, repo);
+        let collection = self
+            .db()
+            .database("packhub")
+            .collection::<RepositoryKey>("keys");
+
+        let key = match (
+            RepositoryKey::retrieve(&collection, &repository).await?,
+            release,
+        ) {
+            (Some(key), _) => key,
+            (None, Some(release)) => {
+                tracing::debug!(
+                    "Generating the key of {repository} for the release {}",
+                    release.tag_name
+                );
+                let cert = {
+                    let passphrase = passphrase.clone();
+                    let repository = repository.clone();
+                    tokio::task::spawn_blocking(move || {
+                        generate_repository_cert(&passphrase, &repository)
+                    })
+                    .await??
+                };
+                let key = RepositoryKey::new(repository, &cert)?;
+                // Another request might have stored a key in the meantime
+                RepositoryKey::insert_if_missing(&collection, &key).await?
+            }
+            (None, None) => bail!("No key was generated for {owner}/{repo}"),
+        };
+
+        let keyring = Arc::new(Keyring::new(key.cert()?, Vec::new(), None));
+        Ok(SigningKeys {
+            signer: Arc::new(KeySigner::new(keyring.clone(), passphrase)),
+            keyring,
+        })
+    }
+}
+
+/// Keys signing the metadata and the packages of a repository.
+///
+/// The public keys are kept along with the signer making the signatures with their secret keys.
+#[derive(Clone)]
+pub struct SigningKeys {
+    keyring: Arc<Keyring>,
+    signer: Arc<dyn Signer>,
+}
+
+impl SigningKeys {
+    /// Run `sign` with the keys on a blocking thread, as the signatures block on the
+    /// cryptography or on the external signer.
+    pub async fn blocking<T, F>(&self, sign: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&SigningKeys) -> Result<T> + Send + 'static,
+    {
+        let keys = self.clone();
+        tokio::task::spawn_blocking(move || sign(&keys)).await?
+    }
+
+    /// Clearsign the metadata with all the active keys.
     pub fn clearsign_metadata(&self, data: &str) -> Result<Vec<u8>> {
-        clearsign_metadata(data, &self.state.cert, &self.state.passphrase)
+        self.signer.clearsign_metadata(data)
     }
 
+    /// Detached sign the metadata with all the active keys.
     pub fn detached_sign_metadata(&self, data: &str) -> Result<Vec<u8>> {
-        detached_sign_metadata(data, &self.state.cert, &self.state.passphrase)
+        self.signer.detached_sign_metadata(data)
+    }
+
+    /// Detached sign the data with the current key.
+    pub fn detached_sign_binary(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.signer.detached_sign_binary(data)
+    }
+
+    /// The active certificates, the current one first.
+    pub fn certs(&self) -> Vec<&Cert> {
+        self.keyring.active()
+    }
+
+    /// Fingerprints of the active certificates in uppercase hex, separated by commas.
+    pub fn fingerprints(&self) -> String {
+        self.keyring
+            .active()
+            .iter()
+            .map(|cert| cert.fingerprint().to_hex())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Fingerprint of the signing certificate in uppercase hex.
+    pub fn fingerprint(&self) -> String {
+        self.keyring.current().fingerprint().to_hex()
+    }
+
+    /// Key ID of the signing key in uppercase hex.
+    pub fn signing_key_id(&self) -> Result<String> {
+        signing_key_id(self.keyring.current())
+    }
+
+    /// User ID of the signing certificate.
+    pub fn user_id(&self) -> String {
+        self.keyring
+            .current()
+            .userids()
+            .next()
+            .map(|uid| uid.userid().to_string())
+            .unwrap_or_default()
     }
 
+    /// Keyring of the active public keys, ASCII armored.
     pub fn armored_public_key(&self) -> Vec<u8> {
-        self.state.cert.armored().to_vec().unwrap()
+        public_keyring(&self.keyring.active(), true).unwrap()
     }
 
+    /// Keyring of the active public keys, in binary.
     pub fn dearmored_public_key(&self) -> Vec<u8> {
-        self.state.cert.to_vec().unwrap()
+        public_keyring(&self.keyring.active(), false).unwrap()
     }
 }
@@ -1,7 +1,9 @@
-use std::{fmt::Display, ops::Add, str::FromStr};
+use std::{io::Write, ops::Add, str::FromStr};
 
 use anyhow::Result;
 use lenient_semver::parse;
+use libflate::gzip::{EncodeOptions, Encoder, HeaderBuilder};
+use liblzma::write::XzEncoder;
 use semver::Version;
 use sha1::digest::{Digest, OutputSizeUser, generic_array::ArrayLength};
 
@@ -50,62 +52,124 @@ impl Dist {
         Dist::Fedora(parse(version).ok())
     }
 
+    #[cfg(test)]
     pub fn leap(version: &str) -> Self {
         Dist::Leap(parse(version).ok())
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash, Default)]
+/// Canonical architecture of a package or a client.
+///
+/// Debian and RPM name the same ABI differently, so every variant maps to
+/// a single ABI and provides its name in both formats with [`Arch::dpkg_name()`]
+/// and [`Arch::rpm_name()`].
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash, Default)]
 pub enum Arch {
     #[default]
     Amd64,
+    I386,
     Arm64,
-    Armhf, // Hard Float ABI ARM. Compatible with armv7 and armv6
-    Armv7,
-    Aarch64,
+    Armhf, // ARMv7 Hard Float ABI
+    Armv6, // ARMv6 Hard Float ABI (Raspberry Pi 1 and Zero). Debian calls it armhf too.
+    Armel, // ARM EABI soft float
     PPC64le,
+    PPC64,
     RiscV64,
     S390x,
+    Mips64el,
+    Loong64,
+    All, // Architecture independent packages (`all` in Debian and `noarch` in RPM)
 }
 
+impl Arch {
+    /// Name of the architecture in Debian.
+    ///
+    /// https://www.debian.org/ports/#portlist-released
+    pub fn dpkg_name(&self) -> &'static str {
+        match self {
+            Arch::Amd64 => "amd64",
+            Arch::I386 => "i386",
+            Arch::Arm64 => "arm64",
+            Arch::Armhf => "armhf",
+            Arch::Armv6 => "armhf",
+            Arch::Armel => "armel",
+            Arch::PPC64le => "ppc64el",
+            Arch::PPC64 => "ppc64",
+            Arch::RiscV64 => "riscv64",
+            Arch::S390x => "s390x",
+            Arch::Mips64el => "mips64el",
+            Arch::Loong64 => "loong64",
+            Arch::All => "all",
+        }
+    }
+
+    /// Name of the architecture in RPM.
+    pub fn rpm_name(&self) -> &'static str {
+        match self {
+            Arch::Amd64 => "x86_64",
+            Arch::I386 => "i686",
+            Arch::Arm64 => "aarch64",
+            Arch::Armhf => "armv7hl",
+            Arch::Armv6 => "armv6hl",
+            Arch::Armel => "armv5tel",
+            Arch::PPC64le => "ppc64le",
+            Arch::PPC64 => "ppc64",
+            Arch::RiscV64 => "riscv64",
+            Arch::S390x => "s390x",
+            Arch::Mips64el => "mips64el",
+            Arch::Loong64 => "loongarch64",
+            Arch::All => "noarch",
+        }
+    }
+
+    /// Architectures whose packages are served to the clients of this architecture,
+    /// in the order of preference.
+    ///
+    /// The 32-bit x86 packages are left out for the 64-bit clients, as they are only
+    /// installed on them as the multilib dependencies of other 32-bit packages.
+    pub fn compatible(&self) -> &'static [Arch] {
+        match self {
+            Arch::Amd64 => &[Arch::Amd64, Arch::All],
+            Arch::I386 => &[Arch::I386, Arch::All],
+            Arch::Arm64 => &[Arch::Arm64, Arch::All],
+            Arch::Armhf => &[Arch::Armhf, Arch::Armv6, Arch::All],
+            Arch::Armv6 => &[Arch::Armv6, Arch::All],
+            Arch::Armel => &[Arch::Armel, Arch::All],
+            Arch::PPC64le => &[Arch::PPC64le, Arch::All],
+            Arch::PPC64 => &[Arch::PPC64, Arch::All],
+            Arch::RiscV64 => &[Arch::RiscV64, Arch::All],
+            Arch::S390x => &[Arch::S390x, Arch::All],
+            Arch::Mips64el => &[Arch::Mips64el, Arch::All],
+            Arch::Loong64 => &[Arch::Loong64, Arch::All],
+            Arch::All => &[Arch::All],
+        }
+    }
+}
+
+/// Parses the Debian, RPM or other commonly used names of an architecture.
 impl FromStr for Arch {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
-            "amd64" => Ok(Arch::Amd64),
-            "x86_64" => Ok(Arch::Amd64),
-            "aarch64" => Ok(Arch::Aarch64),
-            "arm64" => Ok(Arch::Arm64),
-            "armhf" => Ok(Arch::Armhf),
-            "armv6l" => Ok(Arch::Armhf),
-            "armv7" => Ok(Arch::Armv7),
-            "armv7l" => Ok(Arch::Armv7),
-            "ppc64le" => Ok(Arch::PPC64le),
+            "amd64" | "x86_64" | "x64" => Ok(Arch::Amd64),
+            "i386" | "i486" | "i586" | "i686" => Ok(Arch::I386),
+            "arm64" | "aarch64" => Ok(Arch::Arm64),
+            "armhf" | "armhfp" | "armv7" | "armv7l" | "armv7hl" => Ok(Arch::Armhf),
+            "armv6" | "armv6l" | "armv6hl" => Ok(Arch::Armv6),
+            "armel" | "armv5tel" => Ok(Arch::Armel),
+            "ppc64el" | "ppc64le" => Ok(Arch::PPC64le),
+            "ppc64" => Ok(Arch::PPC64),
             "riscv64" => Ok(Arch::RiscV64),
             "s390x" => Ok(Arch::S390x),
+            "mips64el" => Ok(Arch::Mips64el),
+            "loong64" | "loongarch64" => Ok(Arch::Loong64),
+            "all" | "noarch" => Ok(Arch::All),
             _ => Err(()),
         }
     }
 }
 
-// Currently follows the naming convention of Debian
-// https://www.debian.org/ports/#portlist-released
-impl Display for Arch {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Arch::Amd64 => write!(f, "amd64"),
-            Arch::Arm64 => write!(f, "arm64"),
-            Arch::Armhf => write!(f, "armhf"),
-            Arch::Armv7 => write!(f, "armhf"),
-            Arch::Aarch64 => write!(f, "arm64"),
-            Arch::PPC64le => write!(f, "ppc64el"),
-            Arch::RiscV64 => write!(f, "riscv64"),
-            Arch::S390x => write!(f, "s390x"),
-        }
-    }
-}
-
 #[derive(Debug, PartialEq, Clone)]
 pub enum Type {
     Deb,
@@ -121,6 +185,63 @@ impl Type {
     }
 }
 
+/// Compression formats of the repository metadata.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Compression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gz",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zst",
+        }
+    }
+
+    /// Split the `file` name into the name of the uncompressed file and its compression.
+    pub fn split(file: &str) -> Option<(&str, Compression)> {
+        let (name, ext) = file.rsplit_once('.')?;
+        let compression = match ext {
+            "gz" => Compression::Gzip,
+            "xz" => Compression::Xz,
+            "zst" => Compression::Zstd,
+            _ => return None,
+        };
+
+        Some((name, compression))
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::Gzip => gzip_compression(data),
+            Compression::Xz => xz_compression(data),
+            Compression::Zstd => zstd::encode_all(data, 0).unwrap(),
+        }
+    }
+}
+
+pub fn gzip_compression(data: &[u8]) -> Vec<u8> {
+    let header = HeaderBuilder::new().modification_time(0).finish();
+    let options = EncodeOptions::new().header(header);
+    let mut encoder = Encoder::with_options(Vec::new(), options).unwrap();
+    encoder.write_all(data).unwrap();
+
+    let gzip = encoder.finish();
+
+    gzip.into_result().unwrap()
+}
+
+pub fn xz_compression(data: &[u8]) -> Vec<u8> {
+    let mut encoder = XzEncoder::new(Vec::new(), 6);
+    encoder.write_all(data).unwrap();
+
+    encoder.finish().unwrap()
+}
+
 pub fn hashsum<T: Digest>(data: &[u8]) -> String
 where
     <T as OutputSizeUser>::OutputSize: Add,
@@ -168,4 +289,45 @@ mod tests {
         assert!(ver3 > ver0);
         assert!(ver0 < ver3);
     }
+
+    #[test]
+    fn test_arch_names() {
+        assert_eq!("x86_64".parse(), Ok(Arch::Amd64));
+        assert_eq!("aarch64".parse(), Ok(Arch::Arm64));
+        assert_eq!("arm64".parse(), Ok(Arch::Arm64));
+        assert_eq!("armv7hl".parse(), Ok(Arch::Armhf));
+        assert_eq!("armv6l".parse(), Ok(Arch::Armv6));
+        assert_eq!("ppc64el".parse(), Ok(Arch::PPC64le));
+        assert_eq!("noarch".parse(), Ok(Arch::All));
+        assert_eq!("sparc".parse::<Arch>(), Err(()));
+
+        assert_eq!(Arch::Amd64.rpm_name(), "x86_64");
+        assert_eq!(Arch::Arm64.dpkg_name(), "arm64");
+        assert_eq!(Arch::Arm64.rpm_name(), "aarch64");
+        assert_eq!(Arch::Loong64.rpm_name(), "loongarch64");
+
+        // The names of every architecture must be parsed back to it,
+        // except ARMv6 which Debian doesn't distinguish from ARMv7.
+        for arch in [
+            Arch::Amd64,
+            Arch::I386,
+            Arch::Armel,
+            Arch::Mips64el,
+            Arch::All,
+        ] {
+            assert_eq!(arch.dpkg_name().parse(), Ok(arch));
+            assert_eq!(arch.rpm_name().parse(), Ok(arch));
+        }
+        assert_eq!(Arch::Armv6.dpkg_name().parse(), Ok(Arch::Armhf));
+        assert_eq!(Arch::Armv6.rpm_name().parse(), Ok(Arch::Armv6));
+    }
+
+    #[test]
+    fn test_arch_compatibility() {
+        assert!(Arch::Armhf.compatible().contains(&Arch::Armv6));
+        assert!(!Arch::Armv6.compatible().contains(&Arch::Armhf));
+        assert!(Arch::Amd64.compatible().contains(&Arch::All));
+        assert!(!Arch::Arm64.compatible().contains(&Arch::Amd64));
+        assert!(!Arch::Amd64.compatible().contains(&Arch::I386));
+    }
 }
@@ -1,14 +1,32 @@
 #!/bin/sh
 
-echo "Welcome to package key and repository setup script for {{repo}}"
+HOST={{ host|shell }}
+REPO={{ repo|shell }}
+KEY_URL="$HOST/v1/keys/"{{ key|shell }}".gpg"
+KEYRING="/etc/apt/keyrings/"{{ keyring|shell }}".gpg"
+REPO_URL="$HOST/v1/apt/"{{ distro|shell }}"/github/"{{ owner|shell }}"/$REPO"
+{%- if debsig %}
+POLICY_URL="$HOST/v1/keys/"{{ policy|shell }}".pol"
+{%- endif %}
+
+echo "Welcome to package key and repository setup script for $REPO"
 echo "This script will add the repository key and repository to your system."
 echo "Please make sure you have sudo access to run this script."
 echo
 echo "Downloading and installing the repository key..."
-wget -qO- {{host}}/v1/keys/packhub.gpg | sudo tee /etc/apt/keyrings/packhub.gpg > /dev/null
+wget -qO- "$KEY_URL" | sudo tee "$KEYRING" > /dev/null
+echo
+{%- if debsig %}
+echo "Installing the debsig-verify policy of the packages..."
+POLICY=$(wget -qO- "$POLICY_URL")
+KEY_ID=$(echo "$POLICY" | sed -n 's/.*<Origin .* id="\([0-9A-F]*\)".*/\1/p')
+sudo mkdir -p "/etc/debsig/policies/$KEY_ID" "/usr/share/debsig/keyrings/$KEY_ID"
+echo "$POLICY" | sudo tee "/etc/debsig/policies/$KEY_ID/packhub.pol" > /dev/null
+sudo cp "$KEYRING" "/usr/share/debsig/keyrings/$KEY_ID/packhub.gpg"
 echo
+{%- endif %}
 echo "Adding the repository to your system..."
-echo "deb [signed-by=/etc/apt/keyrings/packhub.gpg] {{host}}/v1/apt/{{distro}}/github/{{owner}}/{{repo}} stable main" | sudo tee /etc/apt/sources.list.d/{{repo}}.list > /dev/null
+echo "deb [signed-by=$KEYRING] $REPO_URL stable main" | sudo tee "/etc/apt/sources.list.d/$REPO.list" > /dev/null
 echo 
 echo "Updating package lists..."
 sudo apt-get update
@@ -1,10 +1,16 @@
 #!/bin/sh
 
-echo "Welcome to package key and repository setup script for {{repo}}"
+HOST={{ host|shell }}
+REPO={{ repo|shell }}
+REPO_URL="$HOST/v1/rpm/github/"{{ owner|shell }}"/$REPO"
+KEY_URL="$HOST/v1/keys/"{{ key|shell }}".asc"
+REPO_FILE="/etc/"{{ mgr|shell }}"/$REPO.repo"
+
+echo "Welcome to package key and repository setup script for $REPO"
 echo "This script will add the repository key and repository to your system."
 echo "Please make sure you have sudo access to run this script."
 
-echo -e "[{{repo}}]\nname={{repo}}\nbaseurl={{host}}/v1/rpm/github/{{owner}}/{{repo}}\nenabled=1\ngpgcheck=0\nrepo_gpgcheck=1\ngpgkey={{host}}/v1/keys/packhub.asc" | sudo tee /etc/{{mgr}}/{{repo}}.repo > /dev/null
+printf '[%s]\nname=%s\nbaseurl=%s/$basearch\nenabled=1\ngpgcheck={{ gpgcheck }}\nrepo_gpgcheck=1\ngpgkey=%s\n' "$REPO" "$REPO" "$REPO_URL" "$KEY_URL" | sudo tee "$REPO_FILE" > /dev/null
 
 echo
 echo "Repository has been added to your system."